@@ -0,0 +1,248 @@
+use super::config::{ApiTokenConfig, Role, UserConfig};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use rocket::http::{Cookie, SameSite, Status};
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const SESSION_COOKIE: &str = "session";
+const SESSION_LIFETIME_SEC: i64 = 7 * 24 * 3600;
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug)]
+pub struct Accounts {
+    users: Vec<UserConfig>,
+    tokens: Vec<ApiTokenConfig>,
+}
+
+/// Logged in web UI users by session id.
+#[derive(Debug, Default)]
+pub struct Sessions(Mutex<HashMap<String, Session>>);
+
+#[derive(Debug, Clone)]
+struct Session {
+    user: User,
+    expires: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+}
+
+/// Route guards, each admits users with at least the named role.
+#[derive(Debug)]
+pub struct Viewer;
+#[derive(Debug)]
+pub struct Operator;
+#[derive(Debug)]
+pub struct Admin;
+
+impl Accounts {
+    pub fn new(users: Vec<UserConfig>, tokens: Vec<ApiTokenConfig>) -> Accounts {
+        Accounts { users, tokens }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.tokens.is_empty()
+    }
+
+    /// Unknown users and unparsable hashes fail like a wrong password.
+    pub fn login(&self, name: &str, password: &str) -> Option<User> {
+        let user = self.users.iter().find(|user| user.name == name)?;
+        let hash = match PasswordHash::new(&user.password_hash) {
+            Ok(hash) => hash,
+            Err(e) => {
+                println!("auth: invalid password hash of {}: {}", user.name, e);
+                return None;
+            }
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()?;
+        Some(User {
+            name: user.name.clone(),
+            role: user.role,
+        })
+    }
+
+    pub fn find_token(&self, token: &str) -> Option<User> {
+        let hash = hash_token(token);
+        let token = self
+            .tokens
+            .iter()
+            .find(|t| constant_time_eq(t.token_sha256.as_bytes(), hash.as_bytes()))?;
+        Some(User {
+            name: token.name.clone(),
+            role: token.role,
+        })
+    }
+}
+
+impl Sessions {
+    /// Returns the id of the new session.
+    pub fn open(&self, user: User, now: i64) -> String {
+        let id = hex::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let session = Session {
+            user,
+            expires: now + SESSION_LIFETIME_SEC,
+        };
+        self.0.lock().unwrap().insert(id.clone(), session);
+        id
+    }
+
+    pub fn get(&self, id: &str, now: i64) -> Option<User> {
+        let mut sessions = self.0.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.get(id).map(|session| session.user.clone())
+    }
+
+    pub fn close(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+}
+
+pub fn session_cookie(id: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, id)
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(rocket::time::Duration::seconds(SESSION_LIFETIME_SEC))
+        .finish()
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn new_token() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Scripts send `Authorization: Bearer <token>`, the web UI the session
+/// cookie.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let accounts = req.rocket().state::<Accounts>().unwrap();
+        let sessions = req.rocket().state::<Sessions>().unwrap();
+        let token = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix(BEARER_PREFIX));
+        let user = match token {
+            Some(token) => accounts.find_token(token),
+            None => req
+                .cookies()
+                .get(SESSION_COOKIE)
+                .and_then(|cookie| sessions.get(cookie.value(), chrono::Utc::now().timestamp())),
+        };
+        match user {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+async fn authorize(req: &Request<'_>, role: Role) -> Outcome<(), ()> {
+    let user = try_outcome!(req.guard::<User>().await);
+    if user.role < role {
+        println!("auth: {} may not {} {}", user.name, req.method(), req.uri());
+        return Outcome::Failure((Status::Forbidden, ()));
+    }
+    Outcome::Success(())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Viewer {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(req, Role::Viewer).await.map(|_| Viewer)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(req, Role::Operator).await.map(|_| Operator)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        authorize(req, Role::Admin).await.map(|_| Admin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts() -> Accounts {
+        let users = vec![UserConfig {
+            name: "anna".to_owned(),
+            password_hash: hash_password("correct horse").unwrap(),
+            role: Role::Operator,
+        }];
+        let tokens = vec![ApiTokenConfig {
+            name: "backup script".to_owned(),
+            token_sha256: hash_token("0123abcd"),
+            role: Role::Viewer,
+        }];
+        Accounts::new(users, tokens)
+    }
+
+    #[test]
+    fn test_login() {
+        let accounts = accounts();
+        let user = accounts.login("anna", "correct horse").unwrap();
+        assert_eq!(user.role, Role::Operator);
+        assert!(accounts.login("anna", "wrong").is_none());
+        assert!(accounts.login("bob", "correct horse").is_none());
+
+        let user = accounts.find_token("0123abcd").unwrap();
+        assert_eq!(user.name, "backup script");
+        assert!(accounts.find_token("0123abce").is_none());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::default();
+        let user = User {
+            name: "anna".to_owned(),
+            role: Role::Admin,
+        };
+        let id = sessions.open(user.clone(), 1000);
+        assert_eq!(sessions.get(&id, 1001), Some(user.clone()));
+        assert_eq!(sessions.get("forged", 1001), None);
+        assert_eq!(sessions.get(&id, 1000 + SESSION_LIFETIME_SEC), None);
+
+        let id = sessions.open(user, 1000);
+        sessions.close(&id);
+        assert_eq!(sessions.get(&id, 1001), None);
+    }
+}
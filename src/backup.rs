@@ -0,0 +1,153 @@
+use super::components::{LcnCommand, Metadata, Schedule};
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever a document of the previous version could no longer be
+/// read the same way.
+pub const VERSION: u32 = 1;
+
+/// All tasks of the scheduler, for moving them to another installation.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TaskDocument {
+    pub version: u32,
+    pub tasks: Vec<ExportedTask>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExportedTask {
+    pub schedule: Schedule,
+    pub cmd: LcnCommand,
+    #[serde(default)]
+    pub metadata: Metadata,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Yaml,
+}
+
+/// Merging keeps existing tasks and skips imported tasks equal to one of
+/// them, replacing removes all existing tasks first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    Merge,
+    Replace,
+}
+
+#[derive(Debug)]
+pub struct ImportRequest {
+    pub tasks: Vec<ExportedTask>,
+    pub mode: ImportMode,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub added: Vec<ExportedTask>,
+    pub removed: Vec<i64>,
+    pub skipped: usize,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown format {}", name)),
+        }
+    }
+}
+
+impl ExportedTask {
+    /// Tasks are equal when they run the same command at the same times,
+    /// metadata and enabled state do not matter.
+    pub fn same_task(&self, schedule: &Schedule, cmd: &LcnCommand) -> bool {
+        serde_json::to_value((&self.schedule, &self.cmd)).ok()
+            == serde_json::to_value((schedule, cmd)).ok()
+    }
+}
+
+pub fn render(tasks: Vec<ExportedTask>, format: Format) -> String {
+    let document = TaskDocument {
+        version: VERSION,
+        tasks,
+    };
+    match format {
+        Format::Json => serde_json::to_string_pretty(&document).unwrap(),
+        Format::Yaml => serde_yaml::to_string(&document).unwrap(),
+    }
+}
+
+/// JSON documents are told apart from YAML by their opening brace.
+pub fn parse(text: &str) -> Result<Vec<ExportedTask>, String> {
+    let document: TaskDocument = match text.trim_start().starts_with('{') {
+        true => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        false => serde_yaml::from_str(text).map_err(|e| e.to_string())?,
+    };
+    if document.version > VERSION {
+        return Err(format!(
+            "document version {} is newer than the supported version {}",
+            document.version, VERSION
+        ));
+    }
+    Ok(document.tasks)
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> ExportedTask {
+        let mut schedule = Schedule {
+            hour: 6,
+            min: 30,
+            ..Default::default()
+        };
+        schedule.weekdays[0] = true;
+        ExportedTask {
+            schedule,
+            cmd: LcnCommand::new(1632),
+            metadata: Metadata {
+                name: Some("Shutters up".to_owned()),
+                ..Default::default()
+            },
+            enabled: false,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [Format::Json, Format::Yaml] {
+            let tasks = parse(&render(vec![task()], format)).unwrap();
+            assert_eq!(tasks.len(), 1);
+            assert!(tasks[0].same_task(&task().schedule, &task().cmd));
+            assert_eq!(tasks[0].metadata, task().metadata);
+            assert!(!tasks[0].enabled);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let yaml = "
+version: 1
+tasks:
+  - schedule: { hour: 7, min: 0, sec: 0, weekdays: [true, true, true, true, true, false, false] }
+    cmd: { id: 1623 }
+";
+        let tasks = parse(yaml).unwrap();
+        assert_eq!(tasks[0].cmd.id, 1623);
+        assert!(tasks[0].enabled);
+        assert!(!tasks[0].same_task(&task().schedule, &task().cmd));
+
+        assert!(parse(r#"{"version": 2, "tasks": []}"#).is_err());
+        assert!(parse("tasks: 5").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use super::components::Calendar;
+use super::config::CalendarConfig;
+use super::ics;
+use chrono::{Local, NaiveDate};
+
+/// Recurring events are expanded this far ahead.
+const HORIZON_DAYS: i64 = 5 * 366;
+
+pub fn load(configs: &[CalendarConfig]) -> Vec<Calendar> {
+    let mut calendars = Vec::new();
+    for config in configs {
+        match read(config) {
+            Ok(calendar) => {
+                println!(
+                    "calendars: {} loaded with {} dates",
+                    calendar.name,
+                    calendar.dates.len()
+                );
+                calendars.push(calendar);
+            }
+            Err(e) => println!("calendars: could not load {}: {}", config.name, e),
+        }
+    }
+    calendars
+}
+
+fn read(config: &CalendarConfig) -> Result<Calendar, String> {
+    let text = std::fs::read_to_string(&config.path).map_err(|e| e.to_string())?;
+    let dates = match config.path.ends_with(".ics") {
+        true => {
+            let until = Local::today().naive_local() + chrono::Duration::days(HORIZON_DAYS);
+            ics::event_dates(&text, until)?
+        }
+        false => parse_date_list(&text)?,
+    };
+    Ok(Calendar {
+        name: config.name.clone(),
+        dates: dates.into_iter().collect(),
+    })
+}
+
+/// One YYYY-MM-DD date per line, everything after a `#` is a comment.
+fn parse_date_list(text: &str) -> Result<Vec<NaiveDate>, String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            NaiveDate::parse_from_str(line, "%Y-%m-%d")
+                .map_err(|e| format!("invalid date {}: {}", line, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date_list() {
+        let text = "# public holidays\n2021-12-25  # Christmas\n\n2021-12-26\n";
+        let dates = parse_date_list(text).unwrap();
+        assert_eq!(
+            dates,
+            vec![
+                NaiveDate::from_ymd(2021, 12, 25),
+                NaiveDate::from_ymd(2021, 12, 26)
+            ]
+        );
+        assert!(parse_date_list("25.12.2021").is_err());
+    }
+
+    #[test]
+    fn test_ics_dates() {
+        let text = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20210802\r\n\
+            DTEND;VALUE=DATE:20210805\r\n\
+            SUMMARY:Summer camp\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART:20210810T090000\r\n\
+            DTEND:20210810T120000\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let until = NaiveDate::from_ymd(2030, 1, 1);
+        let dates = ics::event_dates(text, until).unwrap();
+        let day = |d| NaiveDate::from_ymd(2021, 8, d);
+        assert_eq!(dates, vec![day(2), day(3), day(4), day(10)]);
+    }
+
+    #[test]
+    fn test_ics_recurrences() {
+        let text = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20211224\r\n\
+            DTEND;VALUE=DATE:20211227\r\n\
+            RRULE:FREQ=YEARLY\r\n\
+            EXDATE;VALUE=DATE:20221224\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            DTSTART;TZID=Pacific/Auckland:20210811T050000\r\n\
+            RRULE:FREQ=DAILY;INTERVAL=2;COUNT=3\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let dates = ics::event_dates(text, NaiveDate::from_ymd(2023, 12, 25)).unwrap();
+        let date = NaiveDate::from_ymd;
+        assert_eq!(
+            dates,
+            vec![
+                date(2021, 12, 24),
+                date(2021, 12, 25),
+                date(2021, 12, 26),
+                date(2023, 12, 24),
+                date(2023, 12, 25),
+                date(2023, 12, 26),
+                // 5 am in Auckland is still the previous day in Europe
+                date(2021, 8, 10),
+                date(2021, 8, 12),
+                date(2021, 8, 14),
+            ]
+        );
+
+        let monthly = "BEGIN:VEVENT\r\n\
+            DTSTART;VALUE=DATE:20210801\r\n\
+            RRULE:FREQ=MONTHLY\r\n\
+            END:VEVENT\r\n";
+        assert!(ics::event_dates(monthly, date(2022, 1, 1)).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use super::auth;
+use super::config::{Backend, Config};
+use super::lcn;
+use super::secrets::Secrets;
+use std::io::BufRead;
+
+const USAGE: &str = "usage: home_automation [hash-password | new-token | credentials]
+
+  hash-password  reads a password from stdin and prints its hash for the
+                 password_hash of a user in config.json
+  new-token      prints a new API token and the token_sha256 to configure
+  credentials rotate|clear [installation]
+                 logs in again or deletes the cached LCN sessions of one
+                 or all installations";
+
+/// Runs the maintenance command given on the command line and returns the
+/// exit code, or None without a command to start the server.
+pub fn run(args: &[String]) -> Option<i32> {
+    let command = args.first()?;
+    let result = match command.as_str() {
+        "hash-password" => hash_password(),
+        "new-token" => {
+            let token = auth::new_token();
+            println!("token:        {}", token);
+            println!("token_sha256: {}", auth::hash_token(&token));
+            Ok(())
+        }
+        "credentials" => credentials(&args[1..]),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return Some(2);
+        }
+    };
+    match result {
+        Ok(()) => Some(0),
+        Err(e) => {
+            eprintln!("{}: {}", command, e);
+            Some(1)
+        }
+    }
+}
+
+fn hash_password() -> Result<(), String> {
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err("empty password".to_owned());
+    }
+    println!("{}", auth::hash_password(password)?);
+    Ok(())
+}
+
+fn credentials(args: &[String]) -> Result<(), String> {
+    let config = Config::load()?;
+    let installations: Vec<_> = config
+        .installations()
+        .into_iter()
+        // a PCK login can only be cleared, it is asked for again on start
+        .filter(|i| i.backend == Backend::Gvs || args.first().is_some_and(|a| a == "clear"))
+        .filter(|i| args.get(1).is_none_or(|name| *name == i.name))
+        .collect();
+    if installations.is_empty() {
+        return Err("no such installation".to_owned());
+    }
+    let secrets = Secrets::load(&config.secrets)?;
+    for installation in &installations {
+        match args.first().map(String::as_str) {
+            Some("rotate") => {
+                lcn::rotate_credentials(installation, &secrets).map_err(|e| e.to_string())?
+            }
+            Some("clear") => match lcn::clear_credentials(installation) {
+                Ok(true) => println!("[{}] cached login removed", installation.name),
+                Ok(false) => println!("[{}] no cached login", installation.name),
+                Err(e) => return Err(e.to_string()),
+            },
+            _ => return Err("expected rotate or clear".to_owned()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use chrono::{DateTime, Local};
+use std::cell::Cell;
+
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Cell<DateTime<Local>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Local>) -> ManualClock {
+        ManualClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Local>) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Local> {
+        self.now.get()
+    }
+}
@@ -1,9 +1,37 @@
 pub use activation_state::ActivationState;
+pub use alarm::Alarm;
+pub use calendar::Calendar;
+pub use catch_up::CatchUp;
+pub use file_managed::FileManaged;
+pub use last_execution::LastExecution;
 pub use lcn_command::LcnCommand;
+pub use metadata::Metadata;
+pub use occurrence::Occurrence;
 pub use schedule::Schedule;
+pub use vacation::Vacation;
 
 pub mod activation_state;
+pub mod alarm;
+pub mod calendar;
+pub mod catch_up;
+pub mod file_managed;
+pub mod last_execution;
 pub mod lcn_command;
+pub mod metadata;
+pub mod occurrence;
 pub mod schedule;
+pub mod vacation;
 
-lame_ecs::create_component_collection!(ActivationState, LcnCommand, Schedule);
+lame_ecs::create_component_collection!(
+    ActivationState,
+    Alarm,
+    Calendar,
+    CatchUp,
+    FileManaged,
+    LastExecution,
+    LcnCommand,
+    Metadata,
+    Occurrence,
+    Schedule,
+    Vacation
+);
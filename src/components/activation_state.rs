@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum ActivationState {
     ToBeScheduled,
     Scheduled(i64),
     ReadyToRun,
+    Paused,
+    Expired,
 }
@@ -0,0 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+/// A wake-up time pushed by a phone, there is at most one per local day.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Alarm {
+    pub time: i64,
+}
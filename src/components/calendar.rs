@@ -0,0 +1,10 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// A named set of exception dates like public holidays.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Calendar {
+    pub name: String,
+    pub dates: BTreeSet<NaiveDate>,
+}
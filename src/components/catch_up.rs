@@ -0,0 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+/// Marks an immediate command making up for a missed run, repeating the
+/// task's command is intended and must not be debounced.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CatchUp;
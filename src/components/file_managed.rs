@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// Marks a task defined in the tasks file under `key`, it can only be
+/// changed by editing the file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileManaged {
+    pub key: String,
+}
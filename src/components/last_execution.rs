@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+/// The outcome of the latest execution of a task, `failures` counts the
+/// failed executions since the last success and `retries` those of the
+/// current run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct LastExecution {
+    pub time: i64,
+    pub success: bool,
+    pub failures: u32,
+    #[serde(default)]
+    pub retries: u32,
+}
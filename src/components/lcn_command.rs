@@ -1,6 +1,27 @@
+use super::super::pck::PckCommand;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+pub const DEFAULT_INSTALLATION: &str = "default";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct LcnCommand {
     pub id: i32,
+    #[serde(default = "default_installation")]
+    pub installation: String,
+    #[serde(default)]
+    pub pck: Option<PckCommand>,
+}
+
+impl LcnCommand {
+    pub fn new(id: i32) -> LcnCommand {
+        LcnCommand {
+            id,
+            installation: default_installation(),
+            pck: None,
+        }
+    }
+}
+
+fn default_installation() -> String {
+    DEFAULT_INSTALLATION.to_owned()
 }
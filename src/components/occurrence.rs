@@ -0,0 +1,9 @@
+/// The times a task is due at before jitter: the occurrence it is scheduled
+/// for and the last one that became due. The next occurrence is looked for
+/// after the last one, so a run jittered to before its time does not find
+/// the same occurrence again.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Occurrence {
+    pub next: Option<i64>,
+    pub last: Option<i64>,
+}
@@ -1,9 +1,97 @@
+use chrono::NaiveDate;
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Schedule {
     pub hour: i8,
     pub min: i8,
     pub sec: i8,
     pub weekdays: [bool; 7],
+    #[serde(default)]
+    pub jitter_min: u16,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub alarm: Option<AlarmOffset>,
+    #[serde(default)]
+    pub date: Option<NaiveDate>,
+    #[serde(default)]
+    pub calendar: Option<CalendarRef>,
+    #[serde(default)]
+    pub active_from: Option<NaiveDate>,
+    #[serde(default)]
+    pub active_until: Option<NaiveDate>,
+    #[serde(default)]
+    pub misfire: Misfire,
+}
+
+/// Runs the task relative to the alarm pushed for a day instead of at the
+/// fixed time, which is only used on days without an alarm if the fallback
+/// says so.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AlarmOffset {
+    #[serde(default)]
+    pub offset_min: i32,
+    #[serde(default)]
+    pub fallback: AlarmFallback,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlarmFallback {
+    #[default]
+    Default,
+    Skip,
+}
+
+/// Skips the dates of a named exception calendar, or runs only on them.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CalendarRef {
+    pub name: String,
+    #[serde(default)]
+    pub mode: CalendarMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarMode {
+    #[default]
+    Skip,
+    Only,
+}
+
+/// What happens to runs missed while the service was down or the machine
+/// suspended: run once if at most `grace_min` late, skip them or run every
+/// missed occurrence.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum Misfire {
+    RunOnce {
+        #[serde(default = "default_grace_min")]
+        grace_min: u32,
+    },
+    Skip,
+    RunAll,
+}
+
+impl Default for Misfire {
+    fn default() -> Self {
+        Misfire::RunOnce {
+            grace_min: default_grace_min(),
+        }
+    }
+}
+
+fn default_grace_min() -> u32 {
+    60
+}
+
+impl Schedule {
+    pub fn timezone(&self) -> Result<Option<Tz>, String> {
+        match &self.timezone {
+            Some(name) => name.parse::<Tz>().map(Some),
+            None => Ok(None),
+        }
+    }
 }
@@ -0,0 +1,386 @@
+use super::components::lcn_command::DEFAULT_INSTALLATION;
+use super::components::LcnCommand;
+use super::systems::simulation::MAX_SIMULATION_DAYS;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_FILE: &str = "config.json";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Config {
+    #[serde(default)]
+    pub installations: Vec<InstallationConfig>,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub commands: Vec<CommandConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub incoming_hooks: Vec<IncomingHookConfig>,
+    #[serde(default)]
+    pub calendars: Vec<CalendarConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub api_tokens: Vec<ApiTokenConfig>,
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+    #[serde(default)]
+    pub tasks_file: Option<String>,
+    #[serde(default)]
+    pub conflicts: ConflictConfig,
+    #[serde(default)]
+    pub guards: GuardConfig,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InstallationConfig {
+    pub name: String,
+    #[serde(default)]
+    pub addr: Option<String>,
+    #[serde(default)]
+    pub proj: Option<String>,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Gvs,
+    Pck,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub topics: MqttTopics,
+    #[serde(default)]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MqttTopics {
+    pub task_status: String,
+    pub execution_results: String,
+    pub device_states: String,
+    pub commands: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "localhost".to_owned(),
+            port: default_mqtt_port(),
+            client_id: default_mqtt_client_id(),
+            user: None,
+            password: None,
+            topics: MqttTopics::default(),
+            discovery: false,
+            discovery_prefix: default_discovery_prefix(),
+        }
+    }
+}
+
+impl Default for MqttTopics {
+    fn default() -> Self {
+        MqttTopics {
+            task_status: "lcn_scheduler/tasks".to_owned(),
+            execution_results: "lcn_scheduler/results".to_owned(),
+            device_states: "lcn_scheduler/devices".to_owned(),
+            commands: "lcn_scheduler/cmd".to_owned(),
+        }
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "lcn_scheduler".to_owned()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+/// A named LCN command, switches and covers use `off_cmd` to turn off or
+/// close again. `opposes` lists further commands that undo this one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommandConfig {
+    pub name: String,
+    pub cmd: LcnCommand,
+    #[serde(default)]
+    pub kind: CommandKind,
+    #[serde(default)]
+    pub off_cmd: Option<LcnCommand>,
+    #[serde(default)]
+    pub opposes: Vec<LcnCommand>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandKind {
+    #[default]
+    Button,
+    Switch,
+    Cover,
+}
+
+fn catalog_entry<'a>(catalog: &'a [CommandConfig], cmd: &LcnCommand) -> Option<&'a CommandConfig> {
+    catalog.iter().find(|entry| {
+        (entry.cmd.id == cmd.id || entry.off_cmd.as_ref().map(|c| c.id) == Some(cmd.id))
+            && entry.cmd.installation == cmd.installation
+    })
+}
+
+/// Commands of one catalog entry act on the same device, other commands are
+/// devices of their own.
+pub fn device(catalog: &[CommandConfig], cmd: &LcnCommand) -> String {
+    match catalog_entry(catalog, cmd) {
+        Some(entry) => format!("{}/{}", cmd.installation, entry.name),
+        None => format!("{}/{}", cmd.installation, cmd.id),
+    }
+}
+
+/// Whether two commands undo each other, either as the two commands of a
+/// switch or cover or because one lists the other in `opposes`.
+pub fn opposing(catalog: &[CommandConfig], a: &LcnCommand, b: &LcnCommand) -> bool {
+    let same = |x: &LcnCommand, y: &LcnCommand| x.id == y.id && x.installation == y.installation;
+    let declares = |entry: &CommandConfig, x: &LcnCommand, y: &LcnCommand| {
+        same(&entry.cmd, x)
+            && (entry.off_cmd.as_ref().is_some_and(|off| same(off, y))
+                || entry.opposes.iter().any(|other| same(other, y)))
+    };
+    catalog
+        .iter()
+        .any(|entry| declares(entry, a, b) || declares(entry, b, a))
+}
+
+/// Tasks firing opposing commands less than `window_sec` apart conflict.
+/// New tasks are checked over the next `days`, `action` decides whether a
+/// conflict only warns or rejects the task.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ConflictConfig {
+    pub window_sec: i64,
+    pub days: u32,
+    pub action: ConflictAction,
+}
+
+impl Default for ConflictConfig {
+    fn default() -> Self {
+        ConflictConfig {
+            window_sec: 60,
+            days: 7,
+            action: ConflictAction::Warn,
+        }
+    }
+}
+
+/// Per device guards of the executor: commands closer than
+/// `min_interval_sec` to the previous one are deferred, repetitions of the
+/// previous command within `debounce_sec` are dropped, and with `supersede`
+/// a deferred command is dropped when an opposing one follows.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GuardConfig {
+    pub min_interval_sec: i64,
+    pub debounce_sec: i64,
+    pub supersede: bool,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            min_interval_sec: 10,
+            debounce_sec: 30,
+            supersede: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictAction {
+    #[default]
+    Warn,
+    Reject,
+}
+
+/// An outgoing webhook, an empty event list subscribes to all events.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Executed,
+    Failed,
+    Retried,
+    TaskCreated,
+    TaskRemoved,
+    AuthLost,
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+/// A named trigger for a command or a scene of several commands, callers
+/// prove themselves with the shared `secret` or a timestamped HMAC over the
+/// body.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IncomingHookConfig {
+    pub name: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub hmac_key: Option<String>,
+    pub commands: Vec<LcnCommand>,
+}
+
+/// An exception calendar read from an .ics file or a file listing one
+/// YYYY-MM-DD date per line.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CalendarConfig {
+    pub name: String,
+    pub path: String,
+}
+
+/// A web UI account, `password_hash` is an argon2 PHC string as printed by
+/// `home_automation hash-password`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// A token for scripts, only its SHA-256 is kept in the config.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiTokenConfig {
+    pub name: String,
+    pub token_sha256: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// Where the key encrypting cached credentials comes from, without a key
+/// file the passphrase in HOME_AUTOMATION_PASSPHRASE is used.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SecretsConfig {
+    #[serde(default)]
+    pub key_file: Option<String>,
+}
+
+/// Roles are ordered, every role may do what the ones below it may.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let fd = match std::fs::File::open(CONFIG_FILE) {
+            Ok(fd) => fd,
+            Err(_) => {
+                println!("config: no {} found, using defaults", CONFIG_FILE);
+                return Ok(Config::default());
+            }
+        };
+        let reader = std::io::BufReader::new(fd);
+        let mut config: Config = serde_json::from_reader(reader)
+            .map_err(|e| format!("invalid {}: {}", CONFIG_FILE, e))?;
+        if config.conflicts.days > MAX_SIMULATION_DAYS {
+            println!(
+                "config: conflicts.days capped from {} to {}",
+                config.conflicts.days, MAX_SIMULATION_DAYS
+            );
+            config.conflicts.days = MAX_SIMULATION_DAYS;
+        }
+        Ok(config)
+    }
+
+    pub fn installations(&self) -> Vec<InstallationConfig> {
+        if self.installations.is_empty() {
+            return vec![InstallationConfig {
+                name: DEFAULT_INSTALLATION.to_owned(),
+                addr: None,
+                proj: None,
+                backend: Backend::Gvs,
+                user: None,
+                password: None,
+            }];
+        }
+        self.installations.clone()
+    }
+
+    pub fn commands(&self) -> Vec<CommandConfig> {
+        if self.commands.is_empty() {
+            return default_commands();
+        }
+        self.commands.clone()
+    }
+}
+
+/// The commands offered by the web UI.
+fn default_commands() -> Vec<CommandConfig> {
+    let button = |name: &str, id| CommandConfig {
+        name: name.to_owned(),
+        cmd: LcnCommand::new(id),
+        kind: CommandKind::Button,
+        off_cmd: None,
+        opposes: Vec::new(),
+    };
+    vec![
+        button("SZ Lampe", 1623),
+        button("WZ Lampe", 1681),
+        CommandConfig {
+            name: "SZ Rolladen".to_owned(),
+            cmd: LcnCommand::new(1632),
+            kind: CommandKind::Cover,
+            off_cmd: Some(LcnCommand::new(1633)),
+            opposes: Vec::new(),
+        },
+    ]
+}
+
+/// The catalog name of a command, falling back to its id.
+pub fn command_name(catalog: &[CommandConfig], cmd: &LcnCommand) -> String {
+    match catalog_entry(catalog, cmd) {
+        Some(entry) => entry.name.clone(),
+        None => format!("LCN command {}", cmd.id),
+    }
+}
@@ -1,17 +1,64 @@
+use super::calendars;
+use super::clock::SystemClock;
 use super::components::*;
+use super::config::Config;
+use super::events::EventBus;
+use super::guards::Guards;
+use super::history::History;
 use super::lcn;
 use super::requests::*;
+use super::secrets::Secrets;
 use super::systems;
 use rocket::tokio::{runtime::Runtime, sync::mpsc::UnboundedReceiver};
+use std::time::{Duration, Instant};
 
-pub fn run(mut rx: UnboundedReceiver<Request>) -> Result<(), String> {
+/// Installations without a client are retried at most this often.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn run(
+    mut rx: UnboundedReceiver<Request>,
+    config: Config,
+    secrets: Secrets,
+    events: EventBus,
+) -> Result<(), String> {
     let mut world = lame_ecs::create_world!();
     let runtime = Runtime::new().expect("could not create tokio runtime");
     std::thread::sleep(std::time::Duration::from_secs(1));
-    let lcn_client = lcn::build_lcn_client().expect("could not build lcn client");
+    for calendar in calendars::load(&config.calendars) {
+        let entity = world.new_entity();
+        world.add_component(entity, calendar);
+    }
+    let installations = config.installations();
+    let mut lcn_clients = lcn::build_lcn_clients(&installations, &secrets);
+    let mut last_reconnect = Instant::now();
+    let mut history = History::load();
+    let catalog = config.commands();
+    let mut guards = Guards::new(config.guards.clone(), catalog.clone());
+    let clock = SystemClock;
+    let mut rng = rand::thread_rng();
     loop {
-        runtime.block_on(systems::request_processor::process(&mut world, &mut rx))?;
-        systems::scheduler::process(&mut world);
-        systems::lcn_command_executor::process(&mut world, &lcn_client);
+        runtime.block_on(systems::request_processor::process(
+            &mut world,
+            &mut rx,
+            &history,
+            &catalog,
+            &config.conflicts,
+            &clock,
+            &events,
+        ))?;
+        systems::scheduler::process(&mut world, &clock, &mut rng, &events);
+        if lcn_clients.len() < installations.len() && last_reconnect.elapsed() >= RECONNECT_INTERVAL
+        {
+            lcn::reconnect_missing(&installations, &secrets, &mut lcn_clients);
+            last_reconnect = Instant::now();
+        }
+        systems::lcn_command_executor::process(
+            &mut world,
+            &mut lcn_clients,
+            &mut history,
+            &mut guards,
+            &clock,
+            &events,
+        );
     }
 }
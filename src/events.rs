@@ -0,0 +1,78 @@
+use super::components::LcnCommand;
+use super::pck::PckMessage;
+use rocket::tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Immediate commands have no schedule and are gone once executed.
+    TaskCreated {
+        task_id: i64,
+        cmd: LcnCommand,
+        immediate: bool,
+    },
+    TaskRemoved {
+        task_id: i64,
+    },
+    TaskScheduled {
+        task_id: i64,
+        time: i64,
+    },
+    TaskReadyToRun {
+        task_id: i64,
+    },
+    TaskExpired {
+        task_id: i64,
+    },
+    /// `missed` runs since `time` fell into a downtime, `runs` of them are
+    /// made up for.
+    TaskMisfired {
+        task_id: i64,
+        time: i64,
+        missed: u32,
+        runs: u32,
+    },
+    TaskEnabled {
+        task_id: i64,
+        enabled: bool,
+    },
+    CommandExecuted {
+        task_id: i64,
+        cmd: LcnCommand,
+        success: bool,
+        retrying: bool,
+    },
+    DeviceState {
+        installation: String,
+        message: PckMessage,
+    },
+    AuthLost {
+        installation: String,
+    },
+}
+
+/// Fans events out to every subscriber without ever blocking the emitter,
+/// subscribers that went away are dropped on the next emit.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
@@ -0,0 +1,166 @@
+use super::components::LcnCommand;
+use super::config::{self, CommandConfig, GuardConfig};
+use lame_ecs::Entity;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Why a command that was ready to run was not sent.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+pub enum GuardDecision {
+    /// Too close to the previous command to the device, runs at `until`.
+    Deferred { until: i64 },
+    /// The same command was just sent to the device or is already deferred.
+    Debounced,
+    /// Task `by` asked for an opposing command while this one was deferred.
+    Superseded { by: i64 },
+}
+
+/// Remembers the last command sent to each device and the deferred commands
+/// with the time they were deferred to.
+pub struct Guards {
+    config: GuardConfig,
+    catalog: Vec<CommandConfig>,
+    sent: HashMap<String, (LcnCommand, i64)>,
+    deferred: Vec<(Entity, LcnCommand, i64)>,
+}
+
+impl Guards {
+    pub fn new(config: GuardConfig, catalog: Vec<CommandConfig>) -> Guards {
+        Guards {
+            config,
+            catalog,
+            sent: HashMap::new(),
+            deferred: Vec::new(),
+        }
+    }
+
+    /// Returns `None` if the command of `entity` may be sent now. `round`
+    /// collects the commands let through since nothing was sent yet. Catch
+    /// up runs repeat their command on purpose and are only spaced out.
+    pub fn check(
+        &mut self,
+        entity: &Entity,
+        cmd: &LcnCommand,
+        catch_up: bool,
+        now: i64,
+        round: &mut HashMap<String, (LcnCommand, i64)>,
+    ) -> Option<GuardDecision> {
+        self.deferred
+            .retain(|(deferred, _, _)| deferred.id() != entity.id());
+        let device = config::device(&self.catalog, cmd);
+        let debounce = self.config.debounce_sec > 0 && !catch_up;
+        if debounce
+            && self
+                .deferred
+                .iter()
+                .any(|(_, deferred, _)| same(deferred, cmd))
+        {
+            return Some(GuardDecision::Debounced);
+        }
+        let sent = &self.sent;
+        if let Some((last, time)) = round.get(&device).or_else(|| sent.get(&device)) {
+            if debounce && same(last, cmd) && now - time < self.config.debounce_sec {
+                return Some(GuardDecision::Debounced);
+            }
+            if now - time < self.config.min_interval_sec {
+                let until = time + self.config.min_interval_sec;
+                self.deferred.push((*entity, cmd.clone(), until));
+                return Some(GuardDecision::Deferred { until });
+            }
+        }
+        round.insert(device, (cmd.clone(), now));
+        None
+    }
+
+    /// Removes and returns the deferred commands opposing the newer `cmd`.
+    pub fn supersede(&mut self, entity: &Entity, cmd: &LcnCommand) -> Vec<(Entity, LcnCommand)> {
+        if !self.config.supersede {
+            return Vec::new();
+        }
+        let catalog = &self.catalog;
+        let (superseded, kept): (Vec<_>, _) =
+            self.deferred.drain(..).partition(|(deferred, other, _)| {
+                deferred.id() != entity.id() && config::opposing(catalog, other, cmd)
+            });
+        self.deferred = kept;
+        superseded
+            .into_iter()
+            .map(|(deferred, other, _)| (deferred, other))
+            .collect()
+    }
+
+    /// Forgets the deferred commands whose task no longer waits for the time
+    /// it was deferred to, e.g. because it was removed, paused or rescheduled.
+    /// `waiting` tells if the entity still waits for the given time.
+    pub fn retain_deferred(&mut self, mut waiting: impl FnMut(&Entity, i64) -> bool) {
+        self.deferred
+            .retain(|(deferred, _, until)| waiting(deferred, *until));
+    }
+
+    pub fn sent(&mut self, cmd: &LcnCommand, time: i64) {
+        let device = config::device(&self.catalog, cmd);
+        self.sent.insert(device, (cmd.clone(), time));
+    }
+}
+
+fn same(a: &LcnCommand, b: &LcnCommand) -> bool {
+    a.id == b.id && a.installation == b.installation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards() {
+        let catalog = config::Config::default().commands();
+        let mut guards = Guards::new(GuardConfig::default(), catalog);
+        let (up, down, lamp) = (
+            LcnCommand::new(1632),
+            LcnCommand::new(1633),
+            LcnCommand::new(1623),
+        );
+        let mut round = HashMap::new();
+        assert_eq!(
+            guards.check(&Entity::new(0), &up, false, 100, &mut round),
+            None
+        );
+        assert_eq!(
+            guards.check(&Entity::new(1), &lamp, false, 100, &mut round),
+            None
+        );
+        // the first command of the round counts as sent
+        assert_eq!(
+            guards.check(&Entity::new(2), &up, false, 100, &mut round),
+            Some(GuardDecision::Debounced)
+        );
+        guards.sent(&up, 100);
+        guards.sent(&lamp, 100);
+
+        let mut round = HashMap::new();
+        assert_eq!(
+            guards.check(&Entity::new(3), &down, false, 105, &mut round),
+            Some(GuardDecision::Deferred { until: 110 })
+        );
+        assert!(guards.supersede(&Entity::new(3), &down).is_empty());
+        assert_eq!(
+            guards.check(&Entity::new(4), &down, false, 106, &mut round),
+            Some(GuardDecision::Debounced)
+        );
+        // the shutter is up already, but the pending down is no longer wanted
+        assert_eq!(
+            guards.check(&Entity::new(5), &up, false, 107, &mut round),
+            Some(GuardDecision::Debounced)
+        );
+        let superseded = guards.supersede(&Entity::new(5), &up);
+        assert_eq!(superseded.len(), 1);
+        assert_eq!(superseded[0].0.id(), 3);
+
+        let mut round = HashMap::new();
+        assert_eq!(
+            guards.check(&Entity::new(6), &down, false, 110, &mut round),
+            None
+        );
+    }
+}
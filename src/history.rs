@@ -0,0 +1,120 @@
+use super::components::LcnCommand;
+use super::guards::GuardDecision;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+const HISTORY_FILE: &str = "lcn_history";
+const RETENTION_DAYS: i64 = 14;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryEntry {
+    pub time: i64,
+    pub entity: i64,
+    pub cmd: LcnCommand,
+    pub success: bool,
+    /// Set for commands the device guards held back instead of sending.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<GuardDecision>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    persistent: bool,
+}
+
+impl History {
+    pub fn load() -> History {
+        let mut history = History {
+            entries: Vec::new(),
+            persistent: true,
+        };
+        let fd = match std::fs::File::open(HISTORY_FILE) {
+            Ok(fd) => fd,
+            Err(_) => return history,
+        };
+        let oldest = chrono::Local::now().timestamp() - RETENTION_DAYS * 24 * 3600;
+        for line in std::io::BufReader::new(fd).lines().map_while(Result::ok) {
+            match serde_json::from_str::<HistoryEntry>(&line) {
+                Ok(entry) if entry.time >= oldest => history.entries.push(entry),
+                Ok(_) => {}
+                Err(e) => println!("history: skipping invalid entry: {}", e),
+            }
+        }
+        println!("history: {} entries loaded", history.entries.len());
+        history.rewrite();
+        history
+    }
+
+    /// Entries past the retention are dropped once a day, on a long running
+    /// service as well as on the next start.
+    pub fn record(&mut self, entry: HistoryEntry) {
+        let oldest = entry.time - RETENTION_DAYS * 24 * 3600;
+        let expired = self
+            .entries
+            .first()
+            .is_some_and(|first| first.time < oldest - 24 * 3600);
+        if self.persistent {
+            if let Err(e) = append(&entry) {
+                println!("history: could not persist entry: {}", e);
+            }
+        }
+        self.entries.push(entry);
+        if expired {
+            self.entries.retain(|e| e.time >= oldest);
+            println!("history: {} entries kept", self.entries.len());
+            if self.persistent {
+                self.rewrite();
+            }
+        }
+    }
+
+    pub fn since(&self, time: i64) -> impl Iterator<Item = &HistoryEntry> {
+        self.entries.iter().filter(move |e| e.time >= time)
+    }
+
+    fn rewrite(&self) {
+        let result = std::fs::File::create(HISTORY_FILE).and_then(|mut fd| {
+            for entry in &self.entries {
+                writeln!(fd, "{}", serde_json::to_string(entry)?)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            println!("history: could not rewrite history file: {}", e);
+        }
+    }
+}
+
+fn append(entry: &HistoryEntry) -> std::io::Result<()> {
+    let mut fd = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(HISTORY_FILE)?;
+    writeln!(fd, "{}", serde_json::to_string(entry)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_drops_expired_entries() {
+        let mut history = History::default();
+        let day = 24 * 3600;
+        let entry = |time| HistoryEntry {
+            time,
+            entity: 0,
+            cmd: LcnCommand::new(1632),
+            success: true,
+            guard: None,
+        };
+        history.record(entry(0));
+        history.record(entry(RETENTION_DAYS * day));
+        assert_eq!(history.since(0).count(), 2);
+        // pruned a day after the first entry expired
+        history.record(entry((RETENTION_DAYS + 1) * day + 1));
+        assert_eq!(history.since(0).count(), 2);
+        assert!(history.since(0).all(|e| e.time > 0));
+    }
+}
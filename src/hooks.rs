@@ -0,0 +1,205 @@
+use super::auth::constant_time_eq;
+use super::config::IncomingHookConfig;
+use hmac::{Hmac, Mac};
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const SECRET_HEADER: &str = "X-Hook-Secret";
+const SIGNATURE_HEADER: &str = "X-Hook-Signature";
+const TIMESTAMP_HEADER: &str = "X-Hook-Timestamp";
+const SIGNATURE_PREFIX: &str = "sha256=";
+/// Signed calls further than this from the current time are rejected.
+const MAX_SKEW_SEC: i64 = 300;
+
+/// A signed call by its timestamp and signature.
+type SignedCall = (i64, Vec<u8>);
+
+/// The configured hooks along with the timestamps and signatures of the
+/// signed calls accepted within the last `MAX_SKEW_SEC`, each signature may
+/// only be used once.
+#[derive(Debug)]
+pub struct IncomingHooks {
+    hooks: Vec<IncomingHookConfig>,
+    used: Mutex<HashMap<String, Vec<SignedCall>>>,
+}
+
+enum Authenticated {
+    Secret,
+    Signed { timestamp: i64, signature: Vec<u8> },
+}
+
+/// The authentication headers of an incoming hook call, checked against
+/// the hook once the body is known.
+#[derive(Debug)]
+pub struct HookCredentials {
+    secret: Option<String>,
+    signature: Option<String>,
+    timestamp: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for HookCredentials {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(HookCredentials {
+            secret: req.headers().get_one(SECRET_HEADER).map(str::to_owned),
+            signature: req.headers().get_one(SIGNATURE_HEADER).map(str::to_owned),
+            timestamp: req.headers().get_one(TIMESTAMP_HEADER).map(str::to_owned),
+        })
+    }
+}
+
+impl IncomingHooks {
+    pub fn new(hooks: Vec<IncomingHookConfig>) -> IncomingHooks {
+        IncomingHooks {
+            hooks,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&IncomingHookConfig> {
+        self.hooks.iter().find(|hook| hook.name == name)
+    }
+
+    /// Hooks without a secret and HMAC key can never be triggered. Signed
+    /// calls are rejected if they are stale or replay an earlier call.
+    pub fn verify(
+        &self,
+        hook: &IncomingHookConfig,
+        credentials: &HookCredentials,
+        body: &[u8],
+        now: i64,
+    ) -> bool {
+        let call = match authenticate(hook, credentials, body, now) {
+            Some(Authenticated::Secret) => return true,
+            Some(Authenticated::Signed {
+                timestamp,
+                signature,
+            }) => (timestamp, signature),
+            None => return false,
+        };
+        let mut used = self.used.lock().unwrap();
+        let used = used.entry(hook.name.clone()).or_default();
+        used.retain(|(t, _)| *t >= now - MAX_SKEW_SEC);
+        if used.contains(&call) {
+            println!("hooks: rejected replayed call of {}", hook.name);
+            return false;
+        }
+        used.push(call);
+        true
+    }
+}
+
+/// Signatures cover `<timestamp>.<body>`, the timestamp being the unix
+/// time sent in the timestamp header.
+fn authenticate(
+    hook: &IncomingHookConfig,
+    credentials: &HookCredentials,
+    body: &[u8],
+    now: i64,
+) -> Option<Authenticated> {
+    if let (Some(secret), Some(given)) = (&hook.secret, &credentials.secret) {
+        if constant_time_eq(secret.as_bytes(), given.as_bytes()) {
+            return Some(Authenticated::Secret);
+        }
+    }
+    let key = hook.hmac_key.as_ref()?;
+    let signature = credentials.signature.as_ref()?;
+    let timestamp = credentials.timestamp.as_ref()?;
+    let signature = signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .unwrap_or(signature);
+    let signature = hex::decode(signature).ok()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.verify_slice(&signature).ok()?;
+    let timestamp = timestamp.parse::<i64>().ok()?;
+    if (now - timestamp).abs() > MAX_SKEW_SEC {
+        println!("hooks: rejected stale call of {}", hook.name);
+        return None;
+    }
+    Some(Authenticated::Signed {
+        timestamp,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::components::LcnCommand;
+    use super::*;
+
+    fn hook(secret: Option<&str>, hmac_key: Option<&str>) -> IncomingHookConfig {
+        IncomingHookConfig {
+            name: "close_shutters".to_owned(),
+            secret: secret.map(str::to_owned),
+            hmac_key: hmac_key.map(str::to_owned),
+            commands: vec![LcnCommand::new(1633)],
+        }
+    }
+
+    fn credentials(
+        secret: Option<&str>,
+        signature: Option<&str>,
+        timestamp: Option<i64>,
+    ) -> HookCredentials {
+        HookCredentials {
+            secret: secret.map(str::to_owned),
+            signature: signature.map(str::to_owned),
+            timestamp: timestamp.map(|t| t.to_string()),
+        }
+    }
+
+    fn sign(key: &[u8], timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_verify_secret() {
+        let hook = hook(Some("s3cret"), None);
+        let hooks = IncomingHooks::new(vec![hook.clone()]);
+        let verify = |credentials| hooks.verify(&hook, &credentials, b"", 1000);
+        assert!(verify(credentials(Some("s3cret"), None, None)));
+        assert!(verify(credentials(Some("s3cret"), None, None)));
+        assert!(!verify(credentials(Some("s3cre"), None, None)));
+        assert!(!verify(credentials(None, None, None)));
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let hook = hook(None, Some("key"));
+        let hooks = IncomingHooks::new(vec![hook.clone()]);
+        let body = b"{}";
+        let now = 1_623_830_400;
+        let signed = |t| credentials(None, Some(&sign(b"key", t, body)), Some(t));
+        assert!(hooks.verify(&hook, &signed(now), body, now));
+        // replayed
+        assert!(!hooks.verify(&hook, &signed(now), body, now + 1));
+        assert!(!hooks.verify(&hook, &signed(now - 1), b"{ }", now));
+        // another call within the same second
+        let other = credentials(None, Some(&sign(b"key", now, b"[]")), Some(now));
+        assert!(hooks.verify(&hook, &other, b"[]", now));
+        assert!(!hooks.verify(&hook, &credentials(Some("key"), None, None), body, now));
+        // stale, or signed for another time
+        assert!(!hooks.verify(&hook, &signed(now - 301), body, now));
+        let wrong_time = credentials(None, Some(&sign(b"key", now - 2, body)), Some(now - 3));
+        assert!(!hooks.verify(&hook, &wrong_time, body, now));
+        assert!(hooks.verify(&hook, &signed(now - 300), body, now));
+    }
+
+    #[test]
+    fn test_unprotected_hook_is_rejected() {
+        let hook = hook(None, None);
+        let hooks = IncomingHooks::new(vec![hook.clone()]);
+        let credentials = credentials(Some(""), Some(""), Some(0));
+        assert!(!hooks.verify(&hook, &credentials, b"", 0));
+    }
+}
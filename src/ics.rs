@@ -0,0 +1,552 @@
+use super::components::{LcnCommand, Metadata, Schedule};
+use super::config::{command_name, CommandConfig};
+use super::requests::TaskRequest;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+const WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+const COMMAND_PROPERTY: &str = "X-LCN-COMMAND";
+const MAX_LINE_OCTETS: usize = 75;
+
+/// What the calendar export needs to know about a task.
+#[derive(Debug)]
+pub struct CalendarTask {
+    pub id: i64,
+    pub schedule: Schedule,
+    pub cmd: LcnCommand,
+    pub next_run: Option<i64>,
+}
+
+#[derive(Debug)]
+struct Property {
+    name: String,
+    params: Vec<String>,
+    value: String,
+}
+
+pub fn export(tasks: &[CalendarTask], catalog: &[CommandConfig], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//home_automato//LCN schedule//EN".to_owned(),
+    ];
+    for task in tasks {
+        lines.extend(event_lines(task, catalog, now));
+    }
+    lines.push("END:VCALENDAR".to_owned());
+    let mut calendar = String::new();
+    for line in lines {
+        calendar.push_str(&fold(&line));
+        calendar.push_str("\r\n");
+    }
+    calendar
+}
+
+/// Turns every VEVENT into a task request, events that cannot be mapped to
+/// a task are reported instead.
+pub fn import(text: &str, catalog: &[CommandConfig]) -> (Vec<TaskRequest>, Vec<String>) {
+    let mut tasks = Vec::new();
+    let mut errors = Vec::new();
+    let mut event: Option<Vec<Property>> = None;
+    for line in unfold(text) {
+        let property = match parse_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match (property.name.as_str(), property.value.as_str(), &mut event) {
+            ("BEGIN", "VEVENT", _) => event = Some(Vec::new()),
+            ("END", "VEVENT", Some(properties)) => {
+                match event_to_task(properties, catalog) {
+                    Ok(task) => tasks.push(task),
+                    Err(e) => {
+                        let summary = find(properties, "SUMMARY").map(|p| unescape(&p.value));
+                        errors.push(format!("{}: {}", summary.unwrap_or_default(), e));
+                    }
+                }
+                event = None;
+            }
+            (_, _, Some(properties)) => properties.push(property),
+            _ => {}
+        }
+    }
+    (tasks, errors)
+}
+
+/// All dates covered by the events of a calendar up to `until`, in local
+/// time. All-day events may span several days, recurrences are expanded
+/// and their EXDATEs left out.
+pub fn event_dates(text: &str, until: NaiveDate) -> Result<Vec<NaiveDate>, String> {
+    let mut dates = Vec::new();
+    let mut event: Option<Vec<Property>> = None;
+    for line in unfold(text) {
+        let property = match parse_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match (property.name.as_str(), property.value.as_str(), &mut event) {
+            ("BEGIN", "VEVENT", _) => event = Some(Vec::new()),
+            ("END", "VEVENT", Some(properties)) => {
+                dates.extend(expand_event(properties, until)?);
+                event = None;
+            }
+            (_, _, Some(properties)) => properties.push(property),
+            _ => {}
+        }
+    }
+    Ok(dates)
+}
+
+fn expand_event(properties: &[Property], until: NaiveDate) -> Result<Vec<NaiveDate>, String> {
+    let start = find(properties, "DTSTART").ok_or("event without start")?;
+    let first = local_date(start, &start.value)?;
+    // the end of all-day events is exclusive
+    let days = match find(properties, "DTEND") {
+        Some(end) if end.params.iter().any(|p| p == "VALUE=DATE") => {
+            (local_date(end, &end.value)? - first).num_days().max(1)
+        }
+        _ => 1,
+    };
+    let mut excluded = Vec::new();
+    for exdate in properties.iter().filter(|p| p.name == "EXDATE") {
+        for value in exdate.value.split(',') {
+            excluded.push(local_date(exdate, value)?);
+        }
+    }
+    let starts = match find(properties, "RRULE") {
+        Some(rule) => recurrences(&rule.value, first, until)?,
+        None => vec![first],
+    };
+    Ok(starts
+        .into_iter()
+        .filter(|date| !excluded.contains(date))
+        .flat_map(|date| (0..days).map(move |day| date + chrono::Duration::days(day)))
+        .collect())
+}
+
+/// UTC and TZID times are converted to the local time zone, floating times
+/// and dates are taken as they are.
+fn local_date(property: &Property, value: &str) -> Result<NaiveDate, String> {
+    if property.params.iter().any(|p| p == "VALUE=DATE") {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map_err(|e| format!("invalid date {}: {}", value, e));
+    }
+    let (local, utc) = match value.strip_suffix('Z') {
+        Some(local) => (local, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(local, "%Y%m%dT%H%M%S")
+        .map_err(|e| format!("invalid date {}: {}", value, e))?;
+    let tzid = property
+        .params
+        .iter()
+        .find_map(|p| p.strip_prefix("TZID="))
+        .map(|tzid| tzid.trim_matches('"'));
+    let time = match (utc, tzid) {
+        (true, _) => Utc.from_utc_datetime(&time),
+        (false, Some(tzid)) => {
+            let tz = tzid
+                .parse::<Tz>()
+                .map_err(|e| format!("unknown time zone {}: {}", tzid, e))?;
+            match tz.from_local_datetime(&time).earliest() {
+                Some(time) => time.with_timezone(&Utc),
+                // skipped by a DST transition
+                None => return Ok(time.date()),
+            }
+        }
+        (false, None) => return Ok(time.date()),
+    };
+    Ok(time.with_timezone(&Local).naive_local().date())
+}
+
+/// Daily, weekly and yearly rules with an interval, count or end date are
+/// expanded up to `until`, other rules are rejected rather than guessed.
+fn recurrences(rule: &str, first: NaiveDate, until: NaiveDate) -> Result<Vec<NaiveDate>, String> {
+    let unsupported = || format!("unsupported recurrence {}", rule);
+    let mut frequency = None;
+    let mut interval = 1;
+    let mut count = None;
+    let mut end = until;
+    for part in rule.split(';') {
+        match part.split_once('=') {
+            Some(("FREQ", value)) => frequency = Some(value),
+            Some(("INTERVAL", value)) => {
+                interval = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|i| *i > 0)
+                    .ok_or_else(unsupported)?
+            }
+            Some(("COUNT", value)) => {
+                count = Some(value.parse::<usize>().map_err(|_| unsupported())?)
+            }
+            Some(("UNTIL", value)) => {
+                let date = value.get(..8).unwrap_or_default();
+                let date = NaiveDate::parse_from_str(date, "%Y%m%d").map_err(|_| unsupported())?;
+                end = end.min(date);
+            }
+            Some(("WKST", _)) => {}
+            _ => return Err(unsupported()),
+        }
+    }
+    let mut dates = Vec::new();
+    let mut step = 0;
+    while count.is_none_or(|count| dates.len() < count) {
+        let date = match frequency {
+            Some("DAILY") => Some(first + chrono::Duration::days(step * interval)),
+            Some("WEEKLY") => Some(first + chrono::Duration::weeks(step * interval)),
+            Some("YEARLY") => first.with_year(first.year() + (step * interval) as i32),
+            _ => return Err(unsupported()),
+        };
+        step += 1;
+        match date {
+            Some(date) if date > end => break,
+            Some(date) => dates.push(date),
+            // February 29th in a common year
+            None if first.year() as i64 + step * interval <= end.year() as i64 => {}
+            None => break,
+        }
+    }
+    Ok(dates)
+}
+
+fn event_lines(task: &CalendarTask, catalog: &[CommandConfig], now: DateTime<Utc>) -> Vec<String> {
+    let schedule = &task.schedule;
+    let recurring = schedule.date.is_none() && schedule.weekdays.contains(&true);
+    let date = schedule
+        .date
+        .unwrap_or_else(|| start_date(task, now, recurring));
+    let start = format!(
+        "{}T{:02}{:02}{:02}",
+        date.format("%Y%m%d"),
+        schedule.hour,
+        schedule.min,
+        schedule.sec
+    );
+    let dtstart = match &schedule.timezone {
+        Some(tz) => format!("DTSTART;TZID={}:{}", tz, start),
+        None => format!("DTSTART:{}", start),
+    };
+    let cmd = serde_json::to_string(&task.cmd).unwrap();
+    let mut lines = vec![
+        "BEGIN:VEVENT".to_owned(),
+        format!("UID:task-{}@home_automato", task.id),
+        format!("DTSTAMP:{}", now.format("%Y%m%dT%H%M%SZ")),
+        dtstart,
+        "DURATION:PT1M".to_owned(),
+        format!("SUMMARY:{}", escape(&command_name(catalog, &task.cmd))),
+        format!("{}:{}", COMMAND_PROPERTY, escape(&cmd)),
+    ];
+    if recurring {
+        let days: Vec<&str> = WEEKDAYS
+            .iter()
+            .zip(&schedule.weekdays)
+            .filter(|(_, repeat)| **repeat)
+            .map(|(day, _)| *day)
+            .collect();
+        lines.push(format!("RRULE:FREQ=WEEKLY;BYDAY={}", days.join(",")));
+    }
+    if let Some(alarm) = &schedule.alarm {
+        let description = format!("{} min relative to the alarm", alarm.offset_min);
+        lines.push(format!("DESCRIPTION:{}", escape(&description)));
+    }
+    lines.push("END:VEVENT".to_owned());
+    lines
+}
+
+/// The date of the next run in the task's time zone, recurring tasks that
+/// are not scheduled yet start at their next weekday.
+fn start_date(task: &CalendarTask, now: DateTime<Utc>, recurring: bool) -> NaiveDate {
+    let time = task.next_run.unwrap_or_else(|| now.timestamp());
+    let mut date = match task.schedule.timezone() {
+        Ok(Some(tz)) => tz.timestamp(time, 0).naive_local().date(),
+        _ => Local.timestamp(time, 0).naive_local().date(),
+    };
+    while recurring && !task.schedule.weekdays[date.weekday().num_days_from_monday() as usize] {
+        date = date.succ();
+    }
+    date
+}
+
+fn event_to_task(
+    properties: &[Property],
+    catalog: &[CommandConfig],
+) -> Result<TaskRequest, String> {
+    let start = find(properties, "DTSTART").ok_or("no start time")?;
+    if start.params.iter().any(|p| p == "VALUE=DATE") {
+        return Err("all-day events are not supported".to_owned());
+    }
+    let (value, timezone) = match start.value.strip_suffix('Z') {
+        Some(value) => (value, Some("UTC".to_owned())),
+        None => {
+            let tzid = start.params.iter().find_map(|p| p.strip_prefix("TZID="));
+            (start.value.as_str(), tzid.map(str::to_owned))
+        }
+    };
+    let start = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .map_err(|e| format!("invalid start time {}: {}", value, e))?;
+    let weekdays = match find(properties, "RRULE") {
+        Some(rule) => parse_rule(&rule.value, start.date())?,
+        None => [false; 7],
+    };
+    let schedule = Schedule {
+        hour: start.hour() as i8,
+        min: start.minute() as i8,
+        sec: start.second() as i8,
+        weekdays,
+        timezone,
+        date: match weekdays.contains(&true) {
+            true => None,
+            false => Some(start.date()),
+        },
+        ..Default::default()
+    };
+    let cmd = event_command(properties, catalog)?;
+    Ok(TaskRequest {
+        schedule,
+        cmd,
+        metadata: Metadata::default(),
+    })
+}
+
+/// Only plain daily and weekly recurrences map onto weekdays.
+fn parse_rule(rule: &str, start: NaiveDate) -> Result<[bool; 7], String> {
+    let mut frequency = None;
+    let mut days = None;
+    for part in rule.split(';') {
+        match part.split_once('=') {
+            Some(("FREQ", value)) => frequency = Some(value),
+            Some(("BYDAY", value)) => days = Some(value),
+            Some(("INTERVAL", "1")) | Some(("WKST", _)) => {}
+            _ => return Err(format!("unsupported recurrence {}", rule)),
+        }
+    }
+    let mut weekdays = [false; 7];
+    match (frequency, days) {
+        (Some("DAILY"), None) => weekdays = [true; 7],
+        (Some("WEEKLY"), None) => weekdays[start.weekday().num_days_from_monday() as usize] = true,
+        (Some("DAILY"), Some(days)) | (Some("WEEKLY"), Some(days)) => {
+            for day in days.split(',') {
+                let index = WEEKDAYS
+                    .iter()
+                    .position(|d| *d == day)
+                    .ok_or_else(|| format!("unsupported recurrence {}", rule))?;
+                weekdays[index] = true;
+            }
+        }
+        _ => return Err(format!("unsupported recurrence {}", rule)),
+    }
+    Ok(weekdays)
+}
+
+/// Our own exports carry the command itself, other events name a catalogued
+/// command or an LCN command id in their summary.
+fn event_command(properties: &[Property], catalog: &[CommandConfig]) -> Result<LcnCommand, String> {
+    if let Some(cmd) = find(properties, COMMAND_PROPERTY) {
+        return serde_json::from_str(&unescape(&cmd.value))
+            .map_err(|e| format!("invalid {}: {}", COMMAND_PROPERTY, e));
+    }
+    let summary = find(properties, "SUMMARY").map(|p| unescape(&p.value));
+    let summary = summary.ok_or("no summary")?;
+    let summary = summary.trim();
+    if let Some(entry) = catalog
+        .iter()
+        .find(|e| e.name.eq_ignore_ascii_case(summary))
+    {
+        return Ok(entry.cmd.clone());
+    }
+    let id = summary.strip_prefix("LCN command ").unwrap_or(summary);
+    id.parse::<i32>()
+        .map(LcnCommand::new)
+        .map_err(|_| format!("unknown command {}", summary))
+}
+
+fn find<'a>(properties: &'a [Property], name: &str) -> Option<&'a Property> {
+    properties.iter().find(|p| p.name == name)
+}
+
+fn parse_property(line: &str) -> Option<Property> {
+    let (head, value) = line.split_once(':')?;
+    let mut head = head.split(';');
+    let name = head.next()?.to_ascii_uppercase();
+    Some(Property {
+        name,
+        params: head.map(str::to_owned).collect(),
+        value: value.to_owned(),
+    })
+}
+
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (
+            line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')),
+            lines.last_mut(),
+        ) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+    lines
+}
+
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') | Some('N') => result.push('\n'),
+                Some(c) => result.push(c),
+                None => {}
+            },
+            (c, false) => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::Config;
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2021, 6, 16).and_hms(10, 0, 0)
+    }
+
+    fn task(id: i64, schedule: Schedule, cmd_id: i32) -> CalendarTask {
+        CalendarTask {
+            id,
+            schedule,
+            cmd: LcnCommand::new(cmd_id),
+            next_run: None,
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let weekdays = Schedule {
+            hour: 7,
+            weekdays: [true, true, true, true, true, false, false],
+            timezone: Some("Europe/Berlin".to_owned()),
+            ..Default::default()
+        };
+        let once = Schedule {
+            hour: 18,
+            min: 30,
+            date: Some(NaiveDate::from_ymd(2021, 6, 19)),
+            ..Default::default()
+        };
+        let tasks = vec![task(1, weekdays, 1632), task(2, once, 1681)];
+        let calendar = export(&tasks, &Config::default().commands(), now());
+        let lines: Vec<&str> = calendar.split("\r\n").collect();
+        assert_eq!(lines[0], "BEGIN:VCALENDAR");
+        assert!(lines.contains(&"UID:task-1@home_automato"));
+        assert!(lines.contains(&"DTSTART;TZID=Europe/Berlin:20210616T070000"));
+        assert!(lines.contains(&"RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR"));
+        assert!(lines.contains(&"SUMMARY:SZ Rolladen"));
+        assert!(lines.contains(&"DTSTART:20210619T183000"));
+        assert_eq!(lines.iter().filter(|l| l.starts_with("RRULE")).count(), 1);
+        assert!(lines.iter().all(|l| l.len() <= MAX_LINE_OCTETS));
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let schedule = Schedule {
+            hour: 6,
+            min: 45,
+            weekdays: [false, false, false, false, false, true, true],
+            ..Default::default()
+        };
+        let mut cmd = LcnCommand::new(1623);
+        cmd.installation = "apartment, upstairs".to_owned();
+        let tasks = vec![CalendarTask {
+            id: 3,
+            schedule,
+            cmd,
+            next_run: None,
+        }];
+        let calendar = export(&tasks, &[], now());
+        let (imported, errors) = import(&calendar, &[]);
+        assert!(errors.is_empty());
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].schedule.weekdays, tasks[0].schedule.weekdays);
+        assert_eq!(imported[0].schedule.hour, 6);
+        assert_eq!(imported[0].schedule.min, 45);
+        assert_eq!(imported[0].schedule.date, None);
+        assert_eq!(imported[0].cmd.installation, "apartment, upstairs");
+    }
+
+    #[test]
+    fn test_import_calendar_app_events() {
+        let calendar = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            SUMMARY:sz rolladen\r\n\
+            DTSTART;TZID=Europe/Lisbon:20210617T073000\r\n\
+            RRULE:FREQ=WEEKLY;BYDAY=MO,WE\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            SUMMARY:LCN command\r\n  1681\r\n\
+            DTSTART:20210620T200000Z\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            SUMMARY:Birthday\r\n\
+            DTSTART;VALUE=DATE:20210620\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            SUMMARY:Coffee\r\n\
+            DTSTART:20210620T080000\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let (tasks, errors) = import(calendar, &Config::default().commands());
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].cmd.id, 1632);
+        assert_eq!(tasks[0].schedule.timezone.as_deref(), Some("Europe/Lisbon"));
+        assert_eq!(
+            tasks[0].schedule.weekdays,
+            [true, false, true, false, false, false, false]
+        );
+        assert_eq!(tasks[1].cmd.id, 1681);
+        assert_eq!(tasks[1].schedule.timezone.as_deref(), Some("UTC"));
+        assert_eq!(
+            tasks[1].schedule.date,
+            Some(NaiveDate::from_ymd(2021, 6, 20))
+        );
+        assert_eq!(tasks[1].schedule.hour, 20);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("Birthday"));
+        assert!(errors[1].contains("unknown command Coffee"));
+    }
+
+    #[test]
+    fn test_unsupported_recurrence() {
+        let start = NaiveDate::from_ymd(2021, 6, 16);
+        assert!(parse_rule("FREQ=MONTHLY;BYMONTHDAY=1", start).is_err());
+        assert!(parse_rule("FREQ=WEEKLY;INTERVAL=2", start).is_err());
+        assert_eq!(
+            parse_rule("FREQ=WEEKLY", start).unwrap(),
+            [false, false, true, false, false, false, false]
+        );
+    }
+}
@@ -1,27 +1,140 @@
-use reqwest::{blocking::Client, cookie::CookieStore, Url};
-use serde::{Deserialize, Serialize};
+use super::components::lcn_command::DEFAULT_INSTALLATION;
+use super::config::{Backend, InstallationConfig};
+use super::pck::PckClient;
+use super::secrets::{self, Secrets};
+use reqwest::{cookie::CookieStore, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
 use std::{fmt::Display, io, io::Write};
 
 #[derive(Debug)]
 pub struct LcnClient {
-    pub http_client: Client,
+    pub http_client: reqwest::blocking::Client,
     pub home_url: String,
     pub command_url: String,
 }
 
+#[derive(Debug)]
+pub enum Client {
+    Gvs(LcnClient),
+    Pck(PckClient),
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
     Http(reqwest::Error),
     Auth,
+    Secrets(String),
+    LoginRequired,
+}
+
+pub fn build_lcn_clients(
+    installations: &[InstallationConfig],
+    secrets: &Secrets,
+) -> HashMap<String, Client> {
+    let mut clients = HashMap::new();
+    for installation in installations {
+        match build_client(installation, secrets, true) {
+            Ok(client) => {
+                clients.insert(installation.name.clone(), client);
+            }
+            Err(e) => println!(
+                "could not build lcn client for installation {}: {}",
+                installation.name, e
+            ),
+        }
+    }
+    clients
+}
+
+/// Tries again to build the clients that could not be built before. Missing
+/// login data is not asked for, nobody may be watching the terminal by now.
+pub fn reconnect_missing(
+    installations: &[InstallationConfig],
+    secrets: &Secrets,
+    clients: &mut HashMap<String, Client>,
+) {
+    reconnect_with(installations, clients, |installation| {
+        build_client(installation, secrets, false)
+    });
 }
 
-pub fn build_lcn_client() -> Result<LcnClient, Error> {
-    println!("building lcn client:");
+fn reconnect_with(
+    installations: &[InstallationConfig],
+    clients: &mut HashMap<String, Client>,
+    mut build: impl FnMut(&InstallationConfig) -> Result<Client, Error>,
+) {
+    for installation in installations {
+        if clients.contains_key(&installation.name) {
+            continue;
+        }
+        match build(installation) {
+            Ok(client) => {
+                println!("lcn client for installation {} rebuilt", installation.name);
+                clients.insert(installation.name.clone(), client);
+            }
+            Err(e) => println!(
+                "could not rebuild lcn client for installation {}: {}",
+                installation.name, e
+            ),
+        }
+    }
+}
+
+fn build_client(
+    installation: &InstallationConfig,
+    secrets: &Secrets,
+    interactive: bool,
+) -> Result<Client, Error> {
+    match installation.backend {
+        Backend::Gvs => {
+            build_lcn_client(installation, secrets, false, interactive).map(Client::Gvs)
+        }
+        Backend::Pck => build_pck_client(installation, secrets, interactive).map(Client::Pck),
+    }
+}
+
+/// Logs in again and replaces the cached session of a GVS installation.
+pub fn rotate_credentials(
+    installation: &InstallationConfig,
+    secrets: &Secrets,
+) -> Result<(), Error> {
+    build_lcn_client(installation, secrets, true, true).map(|_| ())
+}
+
+/// Returns false if there was no cached session or PCK login.
+pub fn clear_credentials(installation: &InstallationConfig) -> Result<bool, Error> {
+    match std::fs::remove_file(auth_file(&installation.name)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+fn build_lcn_client(
+    installation: &InstallationConfig,
+    secrets: &Secrets,
+    force_login: bool,
+    interactive: bool,
+) -> Result<LcnClient, Error> {
+    println!(
+        "building lcn client for installation {}:",
+        installation.name
+    );
     let jar = std::sync::Arc::new(reqwest::cookie::Jar::default());
     let urls;
+    let auth_file = auth_file(&installation.name);
 
-    let auto_login = load_lcn_auth();
+    // a login sealed with another key must not be overwritten, rotating
+    // replaces it on purpose
+    let auto_login = match force_login {
+        true => Err(Error::Auth),
+        false => match load_auth::<LcnAuth>(&auth_file, secrets) {
+            Err(Error::Secrets(e)) => return Err(Error::Secrets(e)),
+            cached => cached,
+        },
+    };
     let mut manual_login: Option<LcnLogin> = None;
     if let Ok(a) = auto_login {
         println!(" >> cached login info found");
@@ -30,7 +143,7 @@ pub fn build_lcn_client() -> Result<LcnClient, Error> {
             .add_cookie_str(&a.cookie, &urls.base.parse::<Url>().unwrap());
     } else {
         println!(" >> starting manual login");
-        let l = get_login_info()?;
+        let l = get_login_info(installation, interactive)?;
         urls = get_urls(&l.addr, &l.proj);
         manual_login = Some(l);
     }
@@ -57,7 +170,12 @@ pub fn build_lcn_client() -> Result<LcnClient, Error> {
         let cookies = cookies.to_str().map_err(|_| Error::Auth)?;
         let auth_cookie = get_auth_cookie(cookies).ok_or(Error::Auth)?;
         println!(" >> authentication succeeded... saving auth info");
-        save_auth(l.addr, l.proj, auth_cookie)?;
+        let auth = LcnAuth {
+            addr: l.addr,
+            proj: l.proj,
+            cookie: auth_cookie,
+        };
+        save_auth(&auth_file, secrets, &auth)?;
     }
 
     Ok(LcnClient {
@@ -67,10 +185,84 @@ pub fn build_lcn_client() -> Result<LcnClient, Error> {
     })
 }
 
-fn load_lcn_auth() -> Result<LcnAuth, Error> {
-    let fd = std::fs::File::open("lcn_auth")?;
-    let reader = std::io::BufReader::new(fd);
-    Ok(serde_json::from_reader(reader)?)
+/// The PCK login is kept in the auth file of the installation like a GVS
+/// session. A user and password still found in config.json are moved there.
+fn build_pck_client(
+    installation: &InstallationConfig,
+    secrets: &Secrets,
+    interactive: bool,
+) -> Result<PckClient, Error> {
+    println!(
+        "building pck client for installation {}:",
+        installation.name
+    );
+    let name = &installation.name;
+    let auth_file = auth_file(name);
+    let mut addr = installation.addr.clone().unwrap_or_default();
+    if addr.is_empty() {
+        prompt(name, "pchk address", &mut addr, interactive)?;
+    }
+    // a login sealed with another key must not be overwritten
+    let cached = match load_auth::<PckAuth>(&auth_file, secrets) {
+        Err(Error::Secrets(e)) => return Err(Error::Secrets(e)),
+        cached => cached,
+    };
+    let in_config = installation.user.is_some() || installation.password.is_some();
+    let auth = match cached {
+        Ok(auth) if !in_config => {
+            println!(" >> cached login info found");
+            auth
+        }
+        _ => {
+            if in_config {
+                println!(
+                    " >> WARNING: user and password are stored in plain text in config.json, \
+                     they are moved to {}, remove them from config.json",
+                    auth_file
+                );
+            }
+            let mut user = installation.user.clone().unwrap_or_default();
+            let mut password = installation.password.clone().unwrap_or_default();
+            if user.is_empty() {
+                prompt(name, "user name", &mut user, interactive)?;
+            }
+            if password.is_empty() {
+                prompt(name, "password", &mut password, interactive)?;
+            }
+            let auth = PckAuth {
+                user: user.trim_end().to_owned(),
+                password: password.trim_end().to_owned(),
+            };
+            save_auth(&auth_file, secrets, &auth)?;
+            auth
+        }
+    };
+    let mut client = PckClient::new(addr.trim_end().to_owned(), auth.user, auth.password);
+    if let Err(e) = client.connect() {
+        println!(" >> could not connect to pchk yet: {}", e);
+    }
+    Ok(client)
+}
+
+fn auth_file(installation: &str) -> String {
+    match installation {
+        DEFAULT_INSTALLATION => String::from("lcn_auth"),
+        name => format!("lcn_auth_{}", name),
+    }
+}
+
+fn load_auth<T: DeserializeOwned + Serialize>(
+    auth_file: &str,
+    secrets: &Secrets,
+) -> Result<T, Error> {
+    let content = std::fs::read(auth_file)?;
+    let plaintext = secrets.open(&content).map_err(Error::Secrets)?;
+    let auth = serde_json::from_slice(&plaintext)?;
+    if secrets.is_encrypting() && !secrets::is_sealed(&content) {
+        println!(" >> encrypting cached login info");
+        save_auth(auth_file, secrets, &auth)?;
+    }
+    Ok(auth)
 }
 
 fn get_urls(addr: &str, proj: &str) -> Urls {
@@ -86,25 +278,22 @@ fn get_urls(addr: &str, proj: &str) -> Urls {
     }
 }
 
-fn get_login_info() -> Result<LcnLogin, io::Error> {
+fn get_login_info(installation: &InstallationConfig, interactive: bool) -> Result<LcnLogin, Error> {
     let mut login = LcnLogin {
-        addr: String::new(),
-        proj: String::new(),
+        addr: installation.addr.clone().unwrap_or_default(),
+        proj: installation.proj.clone().unwrap_or_default(),
         uname: String::new(),
         passwd: String::new(),
     };
-    print!("ip address: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut login.addr)?;
-    print!("user name: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut login.uname)?;
-    print!("password: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut login.passwd)?;
-    print!("project: ");
-    io::stdout().flush()?;
-    io::stdin().read_line(&mut login.proj)?;
+    let name = &installation.name;
+    if login.addr.is_empty() {
+        prompt(name, "ip address", &mut login.addr, interactive)?;
+    }
+    prompt(name, "user name", &mut login.uname, interactive)?;
+    prompt(name, "password", &mut login.passwd, interactive)?;
+    if login.proj.is_empty() {
+        prompt(name, "project", &mut login.proj, interactive)?;
+    }
     login.addr.truncate(login.addr.trim_end().len());
     login.uname.truncate(login.uname.trim_end().len());
     login.passwd.truncate(login.passwd.trim_end().len());
@@ -115,6 +304,21 @@ fn get_login_info() -> Result<LcnLogin, io::Error> {
     Ok(login)
 }
 
+fn prompt(
+    installation: &str,
+    field: &str,
+    value: &mut String,
+    interactive: bool,
+) -> Result<(), Error> {
+    if !interactive {
+        return Err(Error::LoginRequired);
+    }
+    print!("[{}] {}: ", installation, field);
+    io::stdout().flush()?;
+    io::stdin().read_line(value)?;
+    Ok(())
+}
+
 fn get_project_from_uname(uname: &str) -> String {
     uname.replace("-", " ").replace("/", ".")
 }
@@ -126,10 +330,14 @@ fn get_auth_cookie(cookies: &str) -> Option<String> {
         .map(|x| x.to_owned())
 }
 
-fn save_auth(addr: String, proj: String, cookie: String) -> Result<(), Error> {
-    let mut fd = std::fs::File::create("lcn_auth")?;
-    let x = LcnAuth { addr, proj, cookie };
-    fd.write_all(serde_json::to_string(&x)?.as_bytes())?;
+fn save_auth<T: Serialize>(auth_file: &str, secrets: &Secrets, auth: &T) -> Result<(), Error> {
+    if !secrets.is_encrypting() {
+        println!(" >> no key configured, login info is stored unencrypted");
+    }
+    let content = secrets
+        .seal(&serde_json::to_vec(auth)?)
+        .map_err(Error::Secrets)?;
+    secrets::write_private(auth_file, &content)?;
     Ok(())
 }
 
@@ -162,6 +370,12 @@ struct LcnAuth {
     cookie: String,
 }
 
+#[derive(Deserialize, Serialize)]
+struct PckAuth {
+    user: String,
+    password: String,
+}
+
 #[derive(Debug)]
 struct Urls {
     base: String,
@@ -196,6 +410,60 @@ impl Display for Error {
             Error::Auth => {
                 f.write_str("Authentication error. User name or password might be wrong.")
             }
+            Error::Secrets(e) => f.write_str(e),
+            Error::LoginRequired => {
+                f.write_str("Login data missing, it is asked for when starting in a terminal.")
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::SecretsConfig;
+    use super::*;
+
+    fn installation(name: &str, backend: Backend) -> InstallationConfig {
+        InstallationConfig {
+            name: name.to_owned(),
+            addr: None,
+            proj: None,
+            backend,
+            user: None,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn test_rebuild_does_not_prompt() {
+        let secrets = Secrets::load(&SecretsConfig::default()).unwrap();
+        for backend in [Backend::Gvs, Backend::Pck] {
+            let installation = installation("test_without_login", backend);
+            let result = build_client(&installation, &secrets, false);
+            assert!(matches!(result, Err(Error::LoginRequired)));
+        }
+    }
+
+    #[test]
+    fn test_reconnect_missing_clients() {
+        let installations = vec![
+            installation("default", Backend::Pck),
+            installation("apartment", Backend::Pck),
+            installation("garage", Backend::Pck),
+        ];
+        let pck = || Client::Pck(PckClient::new(String::new(), String::new(), String::new()));
+        let mut clients = HashMap::new();
+        clients.insert("default".to_owned(), pck());
+        let mut built = Vec::new();
+        reconnect_with(&installations, &mut clients, |installation| {
+            built.push(installation.name.clone());
+            match installation.name.as_str() {
+                "apartment" => Ok(pck()),
+                _ => Err(Error::LoginRequired),
+            }
+        });
+        assert_eq!(built, vec!["apartment", "garage"]);
+        assert!(clients.contains_key("apartment"));
+        assert!(!clients.contains_key("garage"));
+    }
+}
@@ -1,16 +1,38 @@
 use requests::*;
+use rocket::form::Form;
+use rocket::http::{ContentType, CookieJar, Status};
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::Redirect;
 use rocket::serde::json::Json;
-use rocket::{get, launch, post, routes, tokio, State};
+use rocket::{get, launch, post, routes, tokio, Shutdown, State};
+use std::collections::HashMap;
 use tokio::sync::{mpsc, mpsc::UnboundedSender};
 
+mod auth;
+mod backup;
+mod calendars;
+mod cli;
+mod clock;
 mod components;
+mod config;
 mod event_loop;
+mod events;
+mod guards;
+mod history;
+mod hooks;
+mod ics;
 mod lcn;
+mod mqtt;
+mod pck;
 mod requests;
+mod secrets;
 mod systems;
+mod tasks_file;
+mod webhooks;
 
 #[post("/new_lcn_task", data = "<task>")]
 fn lcn_task_producer(
+    _user: auth::Operator,
     global_tx: &State<UnboundedSender<Request>>,
     task: Json<TaskRequest>,
 ) -> String {
@@ -18,8 +40,15 @@ fn lcn_task_producer(
     let request = Request::NewTask((tx, task.into_inner()));
     let response = make_request(global_tx, rx, request);
     match response {
-        Ok(Response::NewTask(entity)) => {
-            let res = format!("success: task created with with id {}", entity.id());
+        Ok(Response::NewTask(Ok((entity, warnings)))) => {
+            let mut res = format!("success: task created with with id {}", entity.id());
+            if !warnings.is_empty() {
+                res = format!("{}, warning: {}", res, warnings.join("; "));
+            }
+            serde_json::to_string(&res).unwrap()
+        }
+        Ok(Response::NewTask(Err(e))) => {
+            let res = format!("failure: {}", e);
             serde_json::to_string(&res).unwrap()
         }
         Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
@@ -28,17 +57,235 @@ fn lcn_task_producer(
 }
 
 #[get("/remove_task/<id>")]
-fn remove_task(global_tx: &State<mpsc::UnboundedSender<Request>>, id: i64) -> String {
+fn remove_task(
+    _user: auth::Operator,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    id: i64,
+) -> String {
     let (tx, rx) = tokio::sync::oneshot::channel();
     let request = Request::RemoveTask((tx, lame_ecs::Entity::new(id)));
     let response = make_request(global_tx, rx, request);
     match response {
-        Ok(Response::RemoveTask(true)) => {
+        Ok(Response::RemoveTask(Ok(()))) => {
             let res = format!("success: task with id {} removed", id);
             serde_json::to_string(&res).unwrap()
         }
-        Ok(Response::RemoveTask(false)) => {
-            let res = format!("failure: no task with id {} exists", id);
+        Ok(Response::RemoveTask(Err(e))) => {
+            let res = format!("failure: {}", e);
+            serde_json::to_string(&res).unwrap()
+        }
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[get("/get_status?<sort>&<tag>")]
+fn get_status(
+    _user: auth::Viewer,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    sort: Option<&str>,
+    tag: Option<&str>,
+) -> String {
+    let order = match sort {
+        None | Some("id") => StatusOrder::Id,
+        Some("next_run") => StatusOrder::NextRun,
+        Some(sort) => {
+            let res = format!("failure: cannot sort by {}", sort);
+            return serde_json::to_string(&res).unwrap();
+        }
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::GetStatus((tx, order)));
+    match response {
+        Ok(Response::GetStatus(mut status)) => {
+            if let Some(tag) = tag {
+                status.retain(|task| task.tags.iter().any(|t| t == tag));
+            }
+            serde_json::to_string(&status).unwrap()
+        }
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[post("/start_vacation", data = "<vacation>")]
+fn start_vacation(
+    _user: auth::Operator,
+    global_tx: &State<UnboundedSender<Request>>,
+    vacation: Json<VacationRequest>,
+) -> String {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let request = Request::StartVacation((tx, vacation.into_inner()));
+    let response = make_request(global_tx, rx, request);
+    match response {
+        Ok(Response::StartVacation(tasks)) => {
+            let res = format!("success: vacation mode started with {} tasks", tasks.len());
+            serde_json::to_string(&res).unwrap()
+        }
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[get("/stop_vacation")]
+fn stop_vacation(
+    _user: auth::Operator,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+) -> String {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::StopVacation(tx));
+    match response {
+        Ok(Response::StopVacation(removed)) => {
+            let res = format!("success: vacation mode stopped, {} tasks removed", removed);
+            serde_json::to_string(&res).unwrap()
+        }
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[get("/simulate/<days>")]
+fn simulate(
+    _user: auth::Viewer,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    days: u32,
+) -> String {
+    if days > systems::simulation::MAX_SIMULATION_DAYS {
+        let res = format!(
+            "failure: at most {} days can be simulated",
+            systems::simulation::MAX_SIMULATION_DAYS
+        );
+        return serde_json::to_string(&res).unwrap();
+    }
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::Simulate((tx, days)));
+    match response {
+        Ok(Response::Simulate(runs)) => serde_json::to_string(&runs).unwrap(),
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[get("/conflicts?<days>")]
+fn get_conflicts(
+    _user: auth::Viewer,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    days: Option<u32>,
+) -> String {
+    if days.unwrap_or(0) > systems::simulation::MAX_SIMULATION_DAYS {
+        let res = format!(
+            "failure: at most {} days can be checked",
+            systems::simulation::MAX_SIMULATION_DAYS
+        );
+        return serde_json::to_string(&res).unwrap();
+    }
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::GetConflicts((tx, days)));
+    match response {
+        Ok(Response::GetConflicts(conflicts)) => serde_json::to_string(&conflicts).unwrap(),
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[post("/alarm", data = "<alarm>")]
+fn set_alarm(
+    _user: auth::Operator,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    alarm: Json<AlarmRequest>,
+) -> String {
+    let time = match chrono::DateTime::parse_from_rfc3339(&alarm.time) {
+        Ok(time) => time.timestamp(),
+        Err(e) => return serde_json::to_string(&format!("failure: invalid time: {}", e)).unwrap(),
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::SetAlarm((tx, time)));
+    match response {
+        Ok(Response::SetAlarm(rescheduled)) => {
+            let res = format!("success: alarm set, {} tasks rescheduled", rescheduled);
+            serde_json::to_string(&res).unwrap()
+        }
+        Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
+        Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
+    }
+}
+
+#[get("/schedule.ics")]
+fn export_schedule(
+    _user: auth::Viewer,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    catalog: &State<Vec<config::CommandConfig>>,
+) -> (ContentType, String) {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::ExportSchedule(tx));
+    match response {
+        Ok(Response::ExportSchedule(tasks)) => {
+            let calendar = ics::export(&tasks, catalog, chrono::Utc::now());
+            (ContentType::Calendar, calendar)
+        }
+        Ok(_) => (
+            ContentType::JSON,
+            serde_json::to_string("failure: unexpected response").unwrap(),
+        ),
+        Err(e) => (
+            ContentType::JSON,
+            serde_json::to_string(&e.to_string()).unwrap(),
+        ),
+    }
+}
+
+#[post("/schedule.ics", data = "<calendar>")]
+fn import_schedule(
+    _user: auth::Admin,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    catalog: &State<Vec<config::CommandConfig>>,
+    calendar: String,
+) -> String {
+    let (tasks, mut errors) = ics::import(&calendar, catalog);
+    let mut imported = 0;
+    for task in tasks {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        match make_request(global_tx, rx, Request::NewTask((tx, task))) {
+            Ok(Response::NewTask(Ok(_))) => imported += 1,
+            Ok(Response::NewTask(Err(e))) => errors.push(e),
+            Ok(_) => errors.push("unexpected response".to_owned()),
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    let mut res = format!("success: {} tasks imported", imported);
+    if !errors.is_empty() {
+        res = format!(
+            "{}, {} events skipped: {}",
+            res,
+            errors.len(),
+            errors.join("; ")
+        );
+    }
+    serde_json::to_string(&res).unwrap()
+}
+
+#[post("/tags/<tag>/<action>")]
+fn tag_action(
+    _user: auth::Operator,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    tag: &str,
+    action: &str,
+) -> String {
+    let action = match action {
+        "pause" => TagAction::Pause,
+        "resume" => TagAction::Resume,
+        "remove" => TagAction::Remove,
+        _ => {
+            let res = format!("failure: unknown action {}", action);
+            return serde_json::to_string(&res).unwrap();
+        }
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let request = Request::TagAction((tx, (tag.to_owned(), action)));
+    let response = make_request(global_tx, rx, request);
+    match response {
+        Ok(Response::TagAction(tasks)) => {
+            let res = format!("success: {} tasks tagged {} changed", tasks.len(), tag);
             serde_json::to_string(&res).unwrap()
         }
         Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
@@ -46,29 +293,232 @@ fn remove_task(global_tx: &State<mpsc::UnboundedSender<Request>>, id: i64) -> St
     }
 }
 
-#[get("/get_status")]
-fn get_status(global_tx: &State<mpsc::UnboundedSender<Request>>) -> String {
+#[get("/export?<format>")]
+fn export_tasks(
+    _user: auth::Viewer,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    format: Option<&str>,
+) -> (ContentType, String) {
+    let format = match backup::Format::parse(format.unwrap_or("json")) {
+        Ok(format) => format,
+        Err(e) => {
+            let res = format!("failure: {}", e);
+            return (ContentType::JSON, serde_json::to_string(&res).unwrap());
+        }
+    };
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let response = make_request(global_tx, rx, Request::ExportTasks(tx));
+    match response {
+        Ok(Response::ExportTasks(tasks)) => match format {
+            backup::Format::Json => (ContentType::JSON, backup::render(tasks, format)),
+            backup::Format::Yaml => (
+                ContentType::new("application", "yaml"),
+                backup::render(tasks, format),
+            ),
+        },
+        Ok(_) => (
+            ContentType::JSON,
+            serde_json::to_string("failure: unexpected response").unwrap(),
+        ),
+        Err(e) => (
+            ContentType::JSON,
+            serde_json::to_string(&e.to_string()).unwrap(),
+        ),
+    }
+}
+
+#[post("/import?<mode>&<dry_run>", data = "<document>")]
+fn import_tasks(
+    _user: auth::Admin,
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    mode: Option<&str>,
+    dry_run: Option<bool>,
+    document: String,
+) -> String {
+    let mode = match mode {
+        None | Some("merge") => backup::ImportMode::Merge,
+        Some("replace") => backup::ImportMode::Replace,
+        Some(mode) => {
+            let res = format!("failure: unknown import mode {}", mode);
+            return serde_json::to_string(&res).unwrap();
+        }
+    };
+    let tasks = match backup::parse(&document) {
+        Ok(tasks) => tasks,
+        Err(e) => return serde_json::to_string(&format!("failure: {}", e)).unwrap(),
+    };
+    let request = backup::ImportRequest {
+        tasks,
+        mode,
+        dry_run: dry_run.unwrap_or(false),
+    };
     let (tx, rx) = tokio::sync::oneshot::channel();
-    let response = make_request(global_tx, rx, Request::GetStatus(tx));
+    let response = make_request(global_tx, rx, Request::ImportTasks((tx, request)));
     match response {
-        Ok(Response::GetStatus(status)) => serde_json::to_string(&status).unwrap(),
+        Ok(Response::ImportTasks(Ok(report))) => serde_json::to_string(&report).unwrap(),
+        Ok(Response::ImportTasks(Err(e))) => {
+            serde_json::to_string(&format!("failure: {}", e)).unwrap()
+        }
         Ok(_) => serde_json::to_string("failure: unexpected response").unwrap(),
         Err(e) => serde_json::to_string(&e.to_string()).unwrap(),
     }
 }
 
+/// Streams the events of the scheduler and executor as they happen.
+#[get("/events")]
+fn event_stream(
+    _user: auth::Viewer,
+    events: &State<events::EventBus>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut rx = events.subscribe();
+    EventStream! {
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            yield Event::json(&event);
+        }
+    }
+}
+
+#[post("/hooks/<name>", data = "<body>")]
+fn incoming_hook(
+    global_tx: &State<mpsc::UnboundedSender<Request>>,
+    hooks: &State<hooks::IncomingHooks>,
+    name: &str,
+    credentials: hooks::HookCredentials,
+    body: Vec<u8>,
+) -> Result<String, Status> {
+    let hook = hooks.find(name).ok_or(Status::NotFound)?;
+    if !hooks.verify(hook, &credentials, &body, chrono::Local::now().timestamp()) {
+        println!("hooks: rejected unauthenticated call of {}", name);
+        return Err(Status::Unauthorized);
+    }
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let request = Request::ExecuteScene((tx, hook.commands.clone()));
+    let response = make_request(global_tx, rx, request);
+    match response {
+        Ok(Response::ExecuteScene(tasks)) => {
+            let res = format!("success: {} commands queued", tasks.len());
+            Ok(serde_json::to_string(&res).unwrap())
+        }
+        Ok(_) => Ok(serde_json::to_string("failure: unexpected response").unwrap()),
+        Err(e) => Ok(serde_json::to_string(&e.to_string()).unwrap()),
+    }
+}
+
 #[get("/")]
-fn index() -> rocket_dyn_templates::Template {
-    rocket_dyn_templates::Template::render("home", "")
+#[allow(clippy::result_large_err)] // Redirect is big but built once per page load
+fn index(user: Option<auth::Viewer>) -> Result<rocket_dyn_templates::Template, Redirect> {
+    match user {
+        Some(_) => Ok(rocket_dyn_templates::Template::render("home", "")),
+        None => Err(Redirect::to("/login")),
+    }
+}
+
+#[get("/login?<failed>")]
+fn login_page(failed: Option<bool>) -> rocket_dyn_templates::Template {
+    let context = serde_json::json!({ "failed": failed.unwrap_or(false) });
+    rocket_dyn_templates::Template::render("login", context)
+}
+
+#[post("/login", data = "<form>")]
+fn login(
+    accounts: &State<auth::Accounts>,
+    sessions: &State<auth::Sessions>,
+    cookies: &CookieJar<'_>,
+    form: Form<HashMap<String, String>>,
+) -> Redirect {
+    let field = |name| form.get(name).map(String::as_str).unwrap_or_default();
+    let (user, password) = (field("user"), field("password"));
+    match accounts.login(user, password) {
+        Some(user) => {
+            println!("auth: {} logged in", user.name);
+            let id = sessions.open(user, chrono::Utc::now().timestamp());
+            cookies.add(auth::session_cookie(id));
+            Redirect::to("/")
+        }
+        None => {
+            println!("auth: failed login of {}", user);
+            Redirect::to("/login?failed=true")
+        }
+    }
+}
+
+#[post("/logout")]
+fn logout(sessions: &State<auth::Sessions>, cookies: &CookieJar<'_>) -> Redirect {
+    if let Some(cookie) = cookies.get(auth::SESSION_COOKIE) {
+        sessions.close(cookie.value());
+    }
+    cookies.remove(rocket::http::Cookie::named(auth::SESSION_COOKIE));
+    Redirect::to("/login")
 }
 
 #[launch]
 fn rocket() -> _ {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
     let (tx, rx) = mpsc::unbounded_channel();
-    std::thread::spawn(move || event_loop::run(rx));
+    let config = config::Config::load().unwrap_or_else(|e| panic!("config: {}", e));
+    let accounts = auth::Accounts::new(config.users.clone(), config.api_tokens.clone());
+    if accounts.is_empty() {
+        println!("auth: no users or API tokens configured, see `home_automation help`");
+    }
+    let events = events::EventBus::new();
+    if let Some(mqtt) = config.mqtt.clone() {
+        mqtt::start(mqtt, config.commands(), tx.clone(), &events);
+    }
+    webhooks::start(config.webhooks.clone(), &events);
+    if let Some(path) = config.tasks_file.clone() {
+        tasks_file::start(path, tx.clone());
+    }
+    let incoming_hooks = hooks::IncomingHooks::new(config.incoming_hooks.clone());
+    let catalog = config.commands();
+    let secrets =
+        secrets::Secrets::load(&config.secrets).unwrap_or_else(|e| panic!("secrets: {}", e));
+    if !secrets.is_encrypting() {
+        println!(
+            "secrets: WARNING: no key configured, logins are stored in plain text; \
+             set secrets.key_file or {}",
+            secrets::PASSPHRASE_ENV
+        );
+    }
+    let stream_events = events.clone();
+    std::thread::spawn(move || event_loop::run(rx, config, secrets, events));
     rocket::build()
         .manage(tx)
-        .mount("/", routes![index])
-        .mount("/api", routes![lcn_task_producer, remove_task, get_status])
+        .manage(incoming_hooks)
+        .manage(catalog)
+        .manage(accounts)
+        .manage(stream_events)
+        .manage(auth::Sessions::default())
+        .mount("/", routes![index, login_page, login, logout])
+        .mount(
+            "/api",
+            routes![
+                lcn_task_producer,
+                remove_task,
+                get_status,
+                start_vacation,
+                stop_vacation,
+                simulate,
+                get_conflicts,
+                set_alarm,
+                export_schedule,
+                import_schedule,
+                event_stream,
+                tag_action,
+                export_tasks,
+                import_tasks,
+                incoming_hook
+            ],
+        )
         .attach(rocket_dyn_templates::Template::fairing())
 }
@@ -0,0 +1,471 @@
+use super::components::LcnCommand;
+use super::config::{CommandConfig, MqttConfig, MqttTopics};
+use super::events::{Event, EventBus};
+use super::requests::*;
+use discovery::Discovery;
+use rocket::tokio::sync::{mpsc::UnboundedSender, oneshot};
+use rumqttc::{Client, Connection, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+mod discovery;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct EnableTaskRequest {
+    id: i64,
+    enabled: bool,
+}
+
+/// What the connection loop hands on, it must never wait for the client
+/// whose queue only it drains.
+enum Incoming {
+    Connected,
+    Publish(rumqttc::Publish),
+}
+
+/// Starts the bridge: events from the bus are published to the configured
+/// topics, messages on the command topics are turned into requests.
+pub fn start(
+    config: MqttConfig,
+    catalog: Vec<CommandConfig>,
+    requests: UnboundedSender<Request>,
+    events: &EventBus,
+) {
+    let mut rx = events.subscribe();
+    std::thread::spawn(move || {
+        let (client, connection) = connect(&config);
+        let mut publisher = client.clone();
+        let topics = config.topics.clone();
+        let discovery = match config.discovery {
+            true => Some(Arc::new(Mutex::new(Discovery::new(&config, catalog)))),
+            false => None,
+        };
+        let event_discovery = discovery.clone();
+        std::thread::spawn(move || {
+            while let Some(event) = rx.blocking_recv() {
+                let (topic, payload) = event_message(&topics, &event);
+                if let Err(e) = publisher.publish(topic, QoS::AtLeastOnce, false, payload) {
+                    println!("mqtt: could not publish event: {}", e);
+                }
+                let messages = match &event_discovery {
+                    Some(discovery) => discovery.lock().unwrap().handle_event(&event),
+                    None => continue,
+                };
+                for message in messages {
+                    publish_retained(&mut publisher, message);
+                }
+            }
+        });
+        let (incoming, incoming_rx) = mpsc::channel();
+        let topics = config.topics.clone();
+        std::thread::spawn(move || {
+            handle_incoming(client, incoming_rx, &topics, discovery, &requests)
+        });
+        handle_connection(connection, &incoming);
+    });
+}
+
+fn publish_retained(client: &mut Client, message: discovery::Message) {
+    let result = client.publish(message.topic, QoS::AtLeastOnce, true, message.payload);
+    if let Err(e) = result {
+        println!("mqtt: could not publish discovery message: {}", e);
+    }
+}
+
+fn connect(config: &MqttConfig) -> (Client, Connection) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(user), Some(password)) = (&config.user, &config.password) {
+        options.set_credentials(user, password);
+    }
+    Client::new(options, 64)
+}
+
+fn handle_connection(mut connection: Connection, incoming: &mpsc::Sender<Incoming>) {
+    for notification in connection.iter() {
+        let message = match notification {
+            Ok(rumqttc::Event::Incoming(Packet::ConnAck(_))) => {
+                println!("mqtt: connected");
+                Incoming::Connected
+            }
+            Ok(rumqttc::Event::Incoming(Packet::Publish(publish))) => Incoming::Publish(publish),
+            Ok(_) => continue,
+            Err(e) => {
+                println!("mqtt: connection error: {}", e);
+                std::thread::sleep(RECONNECT_DELAY);
+                continue;
+            }
+        };
+        if incoming.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+/// Makes the calls that wait for room in the client's queue on behalf of
+/// the connection loop.
+fn handle_incoming(
+    mut client: Client,
+    incoming: mpsc::Receiver<Incoming>,
+    topics: &MqttTopics,
+    discovery: Option<Arc<Mutex<Discovery>>>,
+    requests: &UnboundedSender<Request>,
+) {
+    for message in incoming {
+        let publish = match message {
+            Incoming::Connected => {
+                // subscriptions do not survive a reconnect with a clean session
+                let filter = format!("{}/+", topics.commands);
+                if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce) {
+                    println!("mqtt: could not subscribe: {}", e);
+                }
+                if let Some(discovery) = &discovery {
+                    announce(&mut client, discovery, requests);
+                }
+                continue;
+            }
+            Incoming::Publish(publish) => publish,
+        };
+        let prefix = format!("{}/", topics.commands);
+        let command = match publish.topic.strip_prefix(&prefix) {
+            Some(command) => command,
+            None => {
+                // retained entities of tasks that are gone since the last run
+                if let Some(discovery) = &discovery {
+                    let stale = discovery
+                        .lock()
+                        .unwrap()
+                        .handle_retained(&publish.topic, &publish.payload);
+                    for message in stale {
+                        publish_retained(&mut client, message);
+                    }
+                }
+                continue;
+            }
+        };
+        let (topic, response) = match respond(topics, command, &publish.payload, requests) {
+            Some(response) => response,
+            None => continue,
+        };
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, false, response) {
+            println!("mqtt: could not publish response: {}", e);
+        }
+    }
+}
+
+/// Turns a message on a command topic into a request and returns the
+/// topic and payload of the response, responses are not answered.
+fn respond(
+    topics: &MqttTopics,
+    command: &str,
+    payload: &[u8],
+    requests: &UnboundedSender<Request>,
+) -> Option<(String, String)> {
+    if command == "response" {
+        return None;
+    }
+    let response = handle_command(command, payload, requests);
+    println!("mqtt: {}/{} -> {}", topics.commands, command, response);
+    Some((format!("{}/response", topics.commands), response))
+}
+
+/// Announces the catalog and the existing tasks, then looks for retained
+/// entities of tasks that no longer exist.
+fn announce(
+    client: &mut Client,
+    discovery: &Mutex<Discovery>,
+    requests: &UnboundedSender<Request>,
+) {
+    let (tx, rx) = oneshot::channel();
+    let status = match make_request(requests, rx, Request::GetStatus((tx, StatusOrder::Id))) {
+        Ok(Response::GetStatus(status)) => Some(status),
+        Ok(_) => None,
+        Err(e) => {
+            println!("mqtt: could not get the tasks to announce: {}", e);
+            None
+        }
+    };
+    // without the tasks every retained task entity would look stale
+    let (messages, filters) = {
+        let mut discovery = discovery.lock().unwrap();
+        let mut messages = discovery.command_messages();
+        let filters = match status {
+            Some(status) => {
+                messages.extend(discovery.seed(&status));
+                discovery.task_filters()
+            }
+            None => Vec::new(),
+        };
+        (messages, filters)
+    };
+    for message in messages {
+        publish_retained(client, message);
+    }
+    for filter in filters {
+        if let Err(e) = client.subscribe(filter, QoS::AtLeastOnce) {
+            println!("mqtt: could not subscribe: {}", e);
+        }
+    }
+}
+
+fn handle_command(command: &str, payload: &[u8], requests: &UnboundedSender<Request>) -> String {
+    let (tx, rx) = oneshot::channel();
+    let request = match parse_command(command, payload, tx) {
+        Ok(request) => request,
+        Err(e) => return format!("failure: {}", e),
+    };
+    match make_request(requests, rx, request) {
+        Ok(Response::ExecuteCommand(entity)) => {
+            format!("success: command queued as task {}", entity.id())
+        }
+        Ok(Response::NewTask(Ok((entity, warnings)))) => match warnings.is_empty() {
+            true => format!("success: task created with id {}", entity.id()),
+            false => format!(
+                "success: task created with id {}, warning: {}",
+                entity.id(),
+                warnings.join("; ")
+            ),
+        },
+        Ok(Response::NewTask(Err(e))) => format!("failure: {}", e),
+        Ok(Response::RemoveTask(Ok(()))) => "success: task removed".to_owned(),
+        Ok(Response::RemoveTask(Err(e))) => format!("failure: {}", e),
+        Ok(Response::SetTaskEnabled(true)) => "success: task updated".to_owned(),
+        Ok(Response::SetTaskEnabled(false)) => "failure: task unchanged".to_owned(),
+        Ok(_) => "failure: unexpected response".to_owned(),
+        Err(e) => format!("failure: {}", e),
+    }
+}
+
+fn parse_command(
+    command: &str,
+    payload: &[u8],
+    tx: oneshot::Sender<Response>,
+) -> Result<Request, String> {
+    let invalid = |e: serde_json::Error| format!("invalid payload: {}", e);
+    match command {
+        "execute" => {
+            let cmd: LcnCommand = serde_json::from_slice(payload).map_err(invalid)?;
+            Ok(Request::ExecuteCommand((tx, cmd)))
+        }
+        "new_task" => {
+            let task: TaskRequest = serde_json::from_slice(payload).map_err(invalid)?;
+            Ok(Request::NewTask((tx, task)))
+        }
+        "remove_task" => {
+            let id: i64 = serde_json::from_slice(payload).map_err(invalid)?;
+            Ok(Request::RemoveTask((tx, lame_ecs::Entity::new(id))))
+        }
+        "enable_task" => {
+            let r: EnableTaskRequest = serde_json::from_slice(payload).map_err(invalid)?;
+            let entity = lame_ecs::Entity::new(r.id);
+            Ok(Request::SetTaskEnabled((tx, (entity, r.enabled))))
+        }
+        _ => Err(format!("unknown command {}", command)),
+    }
+}
+
+fn event_message(topics: &MqttTopics, event: &Event) -> (String, String) {
+    let topic = match event {
+        Event::TaskCreated { task_id, .. }
+        | Event::TaskRemoved { task_id }
+        | Event::TaskScheduled { task_id, .. }
+        | Event::TaskReadyToRun { task_id }
+        | Event::TaskExpired { task_id }
+        | Event::TaskMisfired { task_id, .. }
+        | Event::TaskEnabled { task_id, .. } => format!("{}/{}", topics.task_status, task_id),
+        Event::CommandExecuted { task_id, .. } => {
+            format!("{}/{}", topics.execution_results, task_id)
+        }
+        Event::DeviceState { installation, .. } | Event::AuthLost { installation } => {
+            format!("{}/{}", topics.device_states, installation)
+        }
+    };
+    (topic, serde_json::to_string(event).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_message() {
+        let topics = MqttTopics::default();
+        let event = Event::TaskScheduled {
+            task_id: 3,
+            time: 1623844800,
+        };
+        let (topic, payload) = event_message(&topics, &event);
+        assert_eq!(topic, "lcn_scheduler/tasks/3");
+        assert_eq!(
+            payload,
+            r#"{"event":"task_scheduled","task_id":3,"time":1623844800}"#
+        );
+
+        let event = Event::CommandExecuted {
+            task_id: 3,
+            cmd: LcnCommand::new(1623),
+            success: true,
+            retrying: false,
+        };
+        assert_eq!(event_message(&topics, &event).0, "lcn_scheduler/results/3");
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let (tx, _rx) = oneshot::channel();
+        match parse_command("execute", br#"{"id": 1623}"#, tx) {
+            Ok(Request::ExecuteCommand((_, cmd))) => assert_eq!(cmd.id, 1623),
+            r => panic!("unexpected request {:?}", r),
+        }
+        let (tx, _rx) = oneshot::channel();
+        match parse_command("remove_task", b"5", tx) {
+            Ok(Request::RemoveTask((_, entity))) => assert_eq!(entity.id(), 5),
+            r => panic!("unexpected request {:?}", r),
+        }
+        let (tx, _rx) = oneshot::channel();
+        assert!(parse_command("execute", b"not json", tx).is_err());
+        let (tx, _rx) = oneshot::channel();
+        assert!(parse_command("reboot", b"", tx).is_err());
+    }
+
+    /// Answers requests like the event loop would, removing only task 3.
+    fn spawn_fake_processor() -> UnboundedSender<Request> {
+        let (tx, mut rx) = rocket::tokio::sync::mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            while let Some(request) = rx.blocking_recv() {
+                let (tx, response) = match request {
+                    Request::ExecuteCommand((tx, _)) => {
+                        (tx, Response::ExecuteCommand(lame_ecs::Entity::new(7)))
+                    }
+                    Request::NewTask((tx, task)) => {
+                        let warnings = vec![format!("command {} conflicts", task.cmd.id)];
+                        let entity = lame_ecs::Entity::new(8);
+                        (tx, Response::NewTask(Ok((entity, warnings))))
+                    }
+                    Request::RemoveTask((tx, entity)) => {
+                        let result = match entity.id() {
+                            3 => Ok(()),
+                            id => Err(format!("no task with id {} exists", id)),
+                        };
+                        (tx, Response::RemoveTask(result))
+                    }
+                    Request::SetTaskEnabled((tx, (_, enabled))) => {
+                        (tx, Response::SetTaskEnabled(enabled))
+                    }
+                    r => panic!("unexpected request {:?}", r),
+                };
+                tx.send(response).unwrap();
+            }
+        });
+        tx
+    }
+
+    #[test]
+    fn test_respond() {
+        let topics = MqttTopics::default();
+        let requests = spawn_fake_processor();
+        let respond = |command: &str, payload: &str| {
+            respond(&topics, command, payload.as_bytes(), &requests).map(|(topic, response)| {
+                assert_eq!(topic, "lcn_scheduler/cmd/response");
+                response
+            })
+        };
+        let answers = [
+            (
+                "execute",
+                r#"{"id": 1623}"#,
+                "success: command queued as task 7",
+            ),
+            (
+                "new_task",
+                r#"{"schedule": {"hour": 7, "min": 0, "sec": 0, "weekdays": [true, true, true, true, true, false, false]}, "cmd": {"id": 1632}}"#,
+                "success: task created with id 8, warning: command 1632 conflicts",
+            ),
+            ("remove_task", "3", "success: task removed"),
+            ("remove_task", "4", "failure: no task with id 4 exists"),
+            (
+                "enable_task",
+                r#"{"id": 3, "enabled": true}"#,
+                "success: task updated",
+            ),
+            (
+                "enable_task",
+                r#"{"id": 3, "enabled": false}"#,
+                "failure: task unchanged",
+            ),
+            (
+                "execute",
+                "{",
+                "failure: invalid payload: EOF while parsing an object at line 1 column 1",
+            ),
+            ("reboot", "", "failure: unknown command reboot"),
+        ];
+        for (command, payload, expected) in answers {
+            assert_eq!(respond(command, payload).as_deref(), Some(expected));
+        }
+        assert_eq!(respond("response", "success: task removed"), None);
+    }
+
+    /// Needs a broker on localhost:1883, e.g. `mosquitto -p 1883`.
+    #[test]
+    #[ignore]
+    fn test_local_broker() {
+        let config = MqttConfig {
+            client_id: "lcn_scheduler_test".to_owned(),
+            ..Default::default()
+        };
+        let events = EventBus::new();
+        let (tx, mut rx) = rocket::tokio::sync::mpsc::unbounded_channel();
+        start(config, Vec::new(), tx, &events);
+
+        let options = MqttOptions::new("lcn_scheduler_test_peer", "localhost", 1883);
+        let (mut peer, mut connection) = Client::new(options, 10);
+        peer.subscribe("lcn_scheduler/#", QoS::AtLeastOnce).unwrap();
+        std::thread::sleep(Duration::from_secs(1));
+        peer.publish(
+            "lcn_scheduler/cmd/execute",
+            QoS::AtLeastOnce,
+            false,
+            r#"{"id": 1623}"#,
+        )
+        .unwrap();
+
+        let responder = std::thread::spawn(move || match rx.blocking_recv() {
+            Some(Request::ExecuteCommand((tx, cmd))) => {
+                assert_eq!(cmd.id, 1623);
+                tx.send(Response::ExecuteCommand(lame_ecs::Entity::new(7)))
+                    .unwrap();
+            }
+            r => panic!("unexpected request {:?}", r),
+        });
+        let mut response = None;
+        let mut state = None;
+        for notification in connection.iter() {
+            if let Ok(rumqttc::Event::Incoming(Packet::Publish(p))) = notification {
+                match p.topic.as_str() {
+                    "lcn_scheduler/cmd/response" => {
+                        response = Some(String::from_utf8_lossy(&p.payload).into_owned());
+                        events.emit(Event::TaskReadyToRun { task_id: 7 });
+                    }
+                    "lcn_scheduler/tasks/7" => {
+                        state = Some(String::from_utf8_lossy(&p.payload).into_owned());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        responder.join().unwrap();
+        assert_eq!(
+            response.unwrap(),
+            "success: command queued as task 7".to_owned()
+        );
+        assert_eq!(
+            state.unwrap(),
+            r#"{"event":"task_ready_to_run","task_id":7}"#
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use super::super::config::{command_name, CommandConfig, CommandKind, MqttConfig, MqttTopics};
+use super::super::events::Event;
+use super::super::systems::status_reporter::TaskStatus;
+use chrono::{DateTime, Local, TimeZone};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const NODE_ID: &str = "lcn_scheduler";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+}
+
+#[derive(Debug)]
+struct TaskState {
+    name: String,
+    next_run: Option<i64>,
+    enabled: bool,
+}
+
+/// Home Assistant MQTT discovery: catalogued commands become buttons,
+/// switches and covers, every task gets a next run sensor and an enable
+/// switch. All messages are meant to be retained.
+#[derive(Debug)]
+pub struct Discovery {
+    prefix: String,
+    topics: MqttTopics,
+    catalog: Vec<CommandConfig>,
+    tasks: HashMap<i64, TaskState>,
+}
+
+impl Discovery {
+    pub fn new(config: &MqttConfig, catalog: Vec<CommandConfig>) -> Discovery {
+        Discovery {
+            prefix: config.discovery_prefix.clone(),
+            topics: config.topics.clone(),
+            catalog,
+            tasks: HashMap::new(),
+        }
+    }
+
+    pub fn command_messages(&self) -> Vec<Message> {
+        self.catalog
+            .iter()
+            .map(|entry| self.command_message(entry))
+            .collect()
+    }
+
+    /// Announces the tasks that existed before the bridge connected, their
+    /// status replaces whatever the events told so far.
+    pub fn seed(&mut self, status: &[TaskStatus]) -> Vec<Message> {
+        self.tasks = status
+            .iter()
+            .map(|task| {
+                let next_run = task
+                    .next_run
+                    .as_deref()
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map(|t| t.timestamp());
+                let state = TaskState {
+                    name: format!("Task {}: {}", task.id, task.cmd_name),
+                    next_run,
+                    enabled: task.state != "Paused",
+                };
+                (task.id, state)
+            })
+            .collect();
+        let mut task_ids: Vec<i64> = self.tasks.keys().copied().collect();
+        task_ids.sort_unstable();
+        let mut messages = Vec::new();
+        for task_id in task_ids {
+            messages.extend(self.task_messages(task_id));
+            messages.push(self.task_state_message(task_id));
+        }
+        messages
+    }
+
+    /// Topic filters matching the retained task entities on the broker.
+    pub fn task_filters(&self) -> Vec<String> {
+        ["sensor", "switch"]
+            .iter()
+            .map(|component| format!("{}/{}/{}/+/config", self.prefix, component, NODE_ID))
+            .collect()
+    }
+
+    /// Clears a retained task entity left behind by a task that no longer
+    /// exists.
+    pub fn handle_retained(&self, topic: &str, payload: &[u8]) -> Vec<Message> {
+        if payload.is_empty() {
+            return Vec::new();
+        }
+        match self.task_id(topic) {
+            Some(task_id) if !self.tasks.contains_key(&task_id) => self.removal_messages(task_id),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event) -> Vec<Message> {
+        let task_id = match event {
+            // immediate commands are no tasks to announce
+            Event::TaskCreated {
+                immediate: true, ..
+            } => return Vec::new(),
+            Event::TaskCreated { task_id, cmd, .. } => {
+                let name = format!("Task {}: {}", task_id, command_name(&self.catalog, cmd));
+                let task = TaskState {
+                    name,
+                    next_run: None,
+                    enabled: true,
+                };
+                self.tasks.insert(*task_id, task);
+                let mut messages = self.task_messages(*task_id);
+                messages.push(self.task_state_message(*task_id));
+                return messages;
+            }
+            Event::TaskRemoved { task_id } => {
+                if self.tasks.remove(task_id).is_none() {
+                    return Vec::new();
+                }
+                return self.removal_messages(*task_id);
+            }
+            Event::TaskScheduled { task_id, time } => match self.tasks.get_mut(task_id) {
+                Some(task) => {
+                    task.next_run = Some(*time);
+                    task_id
+                }
+                None => return Vec::new(),
+            },
+            Event::TaskEnabled { task_id, enabled } => match self.tasks.get_mut(task_id) {
+                Some(task) => {
+                    task.enabled = *enabled;
+                    if !enabled {
+                        task.next_run = None;
+                    }
+                    task_id
+                }
+                None => return Vec::new(),
+            },
+            Event::TaskExpired { task_id } => match self.tasks.get_mut(task_id) {
+                Some(task) => {
+                    task.next_run = None;
+                    task_id
+                }
+                None => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        vec![self.task_state_message(*task_id)]
+    }
+
+    fn command_message(&self, entry: &CommandConfig) -> Message {
+        let object_id = format!("cmd_{}_{}", entry.cmd.installation, entry.cmd.id);
+        let mut config = json!({
+            "name": entry.name,
+            "unique_id": format!("{}_{}", NODE_ID, object_id),
+            "command_topic": format!("{}/execute", self.topics.commands),
+            "device": device(),
+        });
+        let on = serde_json::to_string(&entry.cmd).unwrap();
+        let off = entry
+            .off_cmd
+            .as_ref()
+            .map(|c| serde_json::to_string(c).unwrap());
+        let component = match (entry.kind, off) {
+            (CommandKind::Switch, Some(off)) => {
+                config["payload_on"] = Value::from(on);
+                config["payload_off"] = Value::from(off);
+                config["optimistic"] = Value::from(true);
+                "switch"
+            }
+            (CommandKind::Cover, Some(off)) => {
+                config["payload_open"] = Value::from(on);
+                config["payload_close"] = Value::from(off);
+                config["payload_stop"] = Value::Null;
+                config["optimistic"] = Value::from(true);
+                "cover"
+            }
+            (kind, _) => {
+                if kind != CommandKind::Button {
+                    println!(
+                        "mqtt: {} has no off command, announced as button",
+                        entry.name
+                    );
+                }
+                config["payload_press"] = Value::from(on);
+                "button"
+            }
+        };
+        Message {
+            topic: format!(
+                "{}/{}/{}/{}/config",
+                self.prefix, component, NODE_ID, object_id
+            ),
+            payload: config.to_string(),
+        }
+    }
+
+    fn task_messages(&self, task_id: i64) -> Vec<Message> {
+        let name = &self.tasks[&task_id].name;
+        let state_topic = self.task_state_topic(task_id);
+        let sensor = json!({
+            "name": format!("{} next run", name),
+            "unique_id": format!("{}_task_{}_next_run", NODE_ID, task_id),
+            "state_topic": state_topic,
+            "value_template": "{{ value_json.next_run }}",
+            "device_class": "timestamp",
+            "device": device(),
+        });
+        let switch = json!({
+            "name": format!("{} enabled", name),
+            "unique_id": format!("{}_task_{}_enabled", NODE_ID, task_id),
+            "state_topic": state_topic,
+            "value_template": "{{ 'ON' if value_json.enabled else 'OFF' }}",
+            "command_topic": format!("{}/enable_task", self.topics.commands),
+            "payload_on": json!({ "id": task_id, "enabled": true }).to_string(),
+            "payload_off": json!({ "id": task_id, "enabled": false }).to_string(),
+            "device": device(),
+        });
+        vec![
+            Message {
+                topic: self.sensor_topic(task_id),
+                payload: sensor.to_string(),
+            },
+            Message {
+                topic: self.switch_topic(task_id),
+                payload: switch.to_string(),
+            },
+        ]
+    }
+
+    fn task_state_message(&self, task_id: i64) -> Message {
+        let task = &self.tasks[&task_id];
+        let next_run = task.next_run.map(|t| Local.timestamp(t, 0).to_rfc3339());
+        Message {
+            topic: self.task_state_topic(task_id),
+            payload: json!({ "next_run": next_run, "enabled": task.enabled }).to_string(),
+        }
+    }
+
+    /// Empty retained configs remove the entities again.
+    fn removal_messages(&self, task_id: i64) -> Vec<Message> {
+        vec![
+            self.sensor_topic(task_id),
+            self.switch_topic(task_id),
+            self.task_state_topic(task_id),
+        ]
+        .into_iter()
+        .map(|topic| Message {
+            topic,
+            payload: String::new(),
+        })
+        .collect()
+    }
+
+    /// The task id of a sensor or switch config topic.
+    fn task_id(&self, topic: &str) -> Option<i64> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let parts: Vec<&str> = rest.split('/').collect();
+        let object_id = match parts.as_slice() {
+            [_, node, object_id, "config"] if *node == NODE_ID => object_id,
+            _ => return None,
+        };
+        let id = object_id.strip_prefix("task_")?;
+        let id = id
+            .strip_suffix("_next_run")
+            .or_else(|| id.strip_suffix("_enabled"))?;
+        id.parse().ok()
+    }
+
+    fn sensor_topic(&self, task_id: i64) -> String {
+        format!(
+            "{}/sensor/{}/task_{}_next_run/config",
+            self.prefix, NODE_ID, task_id
+        )
+    }
+
+    fn switch_topic(&self, task_id: i64) -> String {
+        format!(
+            "{}/switch/{}/task_{}_enabled/config",
+            self.prefix, NODE_ID, task_id
+        )
+    }
+
+    fn task_state_topic(&self, task_id: i64) -> String {
+        format!("{}/{}/state", self.topics.task_status, task_id)
+    }
+}
+
+fn device() -> Value {
+    json!({ "identifiers": [NODE_ID], "name": "LCN scheduler" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::components::*;
+    use super::super::super::requests::StatusOrder;
+    use super::super::super::systems::status_reporter::get_status;
+    use super::*;
+    use lame_ecs::create_world;
+
+    fn discovery() -> Discovery {
+        let config = MqttConfig::default();
+        Discovery::new(
+            &config,
+            super::super::super::config::Config::default().commands(),
+        )
+    }
+
+    #[test]
+    fn test_command_messages() {
+        let messages = discovery().command_messages();
+        let topics: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/button/lcn_scheduler/cmd_default_1623/config",
+                "homeassistant/button/lcn_scheduler/cmd_default_1681/config",
+                "homeassistant/cover/lcn_scheduler/cmd_default_1632/config",
+            ]
+        );
+        let cover: Value = serde_json::from_str(&messages[2].payload).unwrap();
+        assert_eq!(cover["name"], "SZ Rolladen");
+        assert_eq!(cover["command_topic"], "lcn_scheduler/cmd/execute");
+        let close: LcnCommand =
+            serde_json::from_str(cover["payload_close"].as_str().unwrap()).unwrap();
+        assert_eq!(close.id, 1633);
+    }
+
+    #[test]
+    fn test_task_lifecycle() {
+        let mut discovery = discovery();
+        let created = discovery.handle_event(&Event::TaskCreated {
+            task_id: 4,
+            cmd: LcnCommand::new(1633),
+            immediate: false,
+        });
+        assert_eq!(created.len(), 3);
+        let switch: Value = serde_json::from_str(&created[1].payload).unwrap();
+        assert_eq!(switch["name"], "Task 4: SZ Rolladen enabled");
+        assert_eq!(switch["state_topic"], "lcn_scheduler/tasks/4/state");
+
+        let time = Local.ymd(2021, 6, 16).and_hms(18, 0, 0);
+        let scheduled = discovery.handle_event(&Event::TaskScheduled {
+            task_id: 4,
+            time: time.timestamp(),
+        });
+        let state: Value = serde_json::from_str(&scheduled[0].payload).unwrap();
+        assert_eq!(state["next_run"], time.to_rfc3339());
+        assert_eq!(state["enabled"], true);
+
+        let paused = discovery.handle_event(&Event::TaskEnabled {
+            task_id: 4,
+            enabled: false,
+        });
+        let state: Value = serde_json::from_str(&paused[0].payload).unwrap();
+        assert_eq!(state["next_run"], Value::Null);
+        assert_eq!(state["enabled"], false);
+
+        let removed = discovery.handle_event(&Event::TaskRemoved { task_id: 4 });
+        assert_eq!(removed.len(), 3);
+        assert!(removed.iter().all(|m| m.payload.is_empty()));
+        assert!(discovery
+            .handle_event(&Event::TaskScheduled {
+                task_id: 4,
+                time: 0
+            })
+            .is_empty());
+    }
+
+    #[test]
+    fn test_seed_and_clear_stale_tasks() {
+        let mut world = create_world!();
+        let task = world.new_entity();
+        let time = Local.ymd(2021, 6, 16).and_hms(18, 0, 0);
+        world.add_component(task, Schedule::default());
+        world.add_component(task, ActivationState::Scheduled(time.timestamp()));
+        world.add_component(task, LcnCommand::new(1633));
+        let mut discovery = discovery();
+        let status = get_status(&world, &discovery.catalog, &time, StatusOrder::Id);
+
+        let seeded = discovery.seed(&status);
+        assert_eq!(seeded.len(), 3);
+        let switch: Value = serde_json::from_str(&seeded[1].payload).unwrap();
+        assert_eq!(switch["name"], "Task 0: SZ Rolladen enabled");
+        let state: Value = serde_json::from_str(&seeded[2].payload).unwrap();
+        assert_eq!(state["next_run"], time.to_rfc3339());
+        assert_eq!(state["enabled"], true);
+
+        let filters = discovery.task_filters();
+        assert_eq!(filters[0], "homeassistant/sensor/lcn_scheduler/+/config");
+        let known = &seeded[0].topic;
+        assert!(discovery.handle_retained(known, b"{}").is_empty());
+        let stale = "homeassistant/switch/lcn_scheduler/task_7_enabled/config";
+        let cleared = discovery.handle_retained(stale, b"{}");
+        let topics: Vec<&str> = cleared.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/lcn_scheduler/task_7_next_run/config",
+                stale,
+                "lcn_scheduler/tasks/7/state",
+            ]
+        );
+        assert!(discovery.handle_retained(stale, b"").is_empty());
+        let command = "homeassistant/switch/lcn_scheduler/cmd_default_1623/config";
+        assert!(discovery.handle_retained(command, b"{}").is_empty());
+    }
+
+    #[test]
+    fn test_immediate_commands_are_not_announced() {
+        let mut discovery = discovery();
+        let created = discovery.handle_event(&Event::TaskCreated {
+            task_id: 5,
+            cmd: LcnCommand::new(1633),
+            immediate: true,
+        });
+        assert!(created.is_empty());
+        assert!(discovery
+            .handle_event(&Event::TaskRemoved { task_id: 5 })
+            .is_empty());
+    }
+}
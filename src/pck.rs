@@ -0,0 +1,514 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Highest ramp code an output command takes.
+const MAX_RAMP: u16 = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Target {
+    Module { segment: u8, id: u8 },
+    Group { segment: u8, id: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayState {
+    On,
+    Off,
+    Toggle,
+    Keep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutterMove {
+    Up,
+    Down,
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PckCommand {
+    Output {
+        target: Target,
+        output: u8,
+        percent: u8,
+        #[serde(default)]
+        ramp: u16,
+    },
+    Relays {
+        target: Target,
+        states: Vec<RelayState>,
+    },
+    Shutter {
+        target: Target,
+        motor: u8,
+        movement: ShutterMove,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PckMessage {
+    Ack {
+        segment: u8,
+        id: u8,
+    },
+    Nack {
+        segment: u8,
+        id: u8,
+        code: u16,
+    },
+    Output {
+        segment: u8,
+        id: u8,
+        output: u8,
+        percent: u8,
+    },
+    Relays {
+        segment: u8,
+        id: u8,
+        states: [bool; 8],
+    },
+    Other {
+        text: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Auth,
+    Nack(u16),
+    Timeout,
+    InvalidCommand(String),
+}
+
+#[derive(Debug)]
+pub struct PckClient {
+    addr: String,
+    user: String,
+    password: String,
+    connection: Option<Connection>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+/// Tries every address the name resolves to, an unreachable host must not
+/// block the executor.
+fn connect_timeout(addr: &str) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address resolves to nothing")
+    }))
+}
+
+impl PckClient {
+    pub fn new(addr: String, user: String, password: String) -> PckClient {
+        PckClient {
+            addr,
+            user,
+            password,
+            connection: None,
+        }
+    }
+
+    pub fn connect(&mut self) -> Result<(), Error> {
+        println!("pck: connecting to {}", self.addr);
+        let stream = connect_timeout(&self.addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        connection.wait_for("Username:")?;
+        connection.send(&self.user)?;
+        connection.wait_for("Password:")?;
+        connection.send(&self.password)?;
+        loop {
+            let line = connection.read_line()?;
+            if line.starts_with("OK") {
+                break;
+            }
+            if line.contains("failed") {
+                return Err(Error::Auth);
+            }
+        }
+        println!("pck: authenticated at {}", self.addr);
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Sends the command and, for modules, waits for their acknowledgement.
+    /// Status messages received while waiting are returned to the caller.
+    pub fn execute(&mut self, command: &PckCommand) -> Result<Vec<PckMessage>, Error> {
+        let encoded = encode(command)?;
+        if self.connection.is_none() {
+            self.connect()?;
+        }
+        let result = self.execute_encoded(command, &encoded);
+        if let Err(Error::Io(_)) | Err(Error::Timeout) = result {
+            // force a fresh connection on the next command
+            self.connection = None;
+        }
+        result
+    }
+
+    fn execute_encoded(
+        &mut self,
+        command: &PckCommand,
+        encoded: &str,
+    ) -> Result<Vec<PckMessage>, Error> {
+        let connection = self.connection.as_mut().ok_or(Error::Timeout)?;
+        connection.send(encoded)?;
+        let (segment, id) = match target(command) {
+            Target::Module { segment, id } => (segment, id),
+            Target::Group { .. } => return Ok(Vec::new()),
+        };
+        let deadline = Instant::now() + TIMEOUT;
+        let mut messages = Vec::new();
+        while Instant::now() < deadline {
+            match parse_message(&connection.read_line()?) {
+                PckMessage::Ack { segment: s, id: i } if s == segment && i == id => {
+                    return Ok(messages)
+                }
+                PckMessage::Nack {
+                    segment: s,
+                    id: i,
+                    code,
+                } if s == segment && i == id => return Err(Error::Nack(code)),
+                message => messages.push(message),
+            }
+        }
+        Err(Error::Timeout)
+    }
+}
+
+impl Connection {
+    fn send(&mut self, line: &str) -> Result<(), Error> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line)? {
+            0 => Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into())),
+            _ => Ok(line.trim_end().to_owned()),
+        }
+    }
+
+    /// Prompts are not terminated by a line break, so they are matched
+    /// against the raw input instead of whole lines.
+    fn wait_for(&mut self, prompt: &str) -> Result<(), Error> {
+        let mut received = Vec::new();
+        while !String::from_utf8_lossy(&received).contains(prompt) {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            let length = buffer.len();
+            received.extend_from_slice(buffer);
+            self.reader.consume(length);
+        }
+        Ok(())
+    }
+}
+
+pub fn encode(command: &PckCommand) -> Result<String, Error> {
+    let body = match command {
+        PckCommand::Output {
+            output,
+            percent,
+            ramp,
+            ..
+        } => {
+            if !(1..=4).contains(output) || *percent > 100 || *ramp > MAX_RAMP {
+                return Err(Error::InvalidCommand(format!(
+                    "output {} at {}% with ramp {}",
+                    output, percent, ramp
+                )));
+            }
+            format!("A{}DI{:03}{:03}", output, percent, ramp)
+        }
+        PckCommand::Relays { states, .. } => {
+            if states.len() > 8 {
+                return Err(Error::InvalidCommand(format!("{} relays", states.len())));
+            }
+            let mut body = String::from("R8");
+            for i in 0..8 {
+                body.push(match states.get(i) {
+                    Some(RelayState::On) => '1',
+                    Some(RelayState::Off) => '0',
+                    Some(RelayState::Toggle) => 'U',
+                    Some(RelayState::Keep) | None => '-',
+                });
+            }
+            body
+        }
+        PckCommand::Shutter {
+            motor, movement, ..
+        } => {
+            if !(1..=4).contains(motor) {
+                return Err(Error::InvalidCommand(format!("shutter motor {}", motor)));
+            }
+            // every motor uses a pair of relays: power and direction
+            let pair = match movement {
+                ShutterMove::Up => "10",
+                ShutterMove::Down => "11",
+                ShutterMove::Stop => "0-",
+            };
+            let mut relays = String::from("--------");
+            let first = (*motor as usize - 1) * 2;
+            relays.replace_range(first..first + 2, pair);
+            format!("R8{}", relays)
+        }
+    };
+    let address = match target(command) {
+        Target::Module { segment, id } => format!(">M{:03}{:03}!", segment, id),
+        Target::Group { segment, id } => format!(">G{:03}{:03}.", segment, id),
+    };
+    Ok(address + &body)
+}
+
+pub fn parse_message(line: &str) -> PckMessage {
+    parse_known_message(line).unwrap_or_else(|| PckMessage::Other {
+        text: line.to_owned(),
+    })
+}
+
+fn parse_known_message(line: &str) -> Option<PckMessage> {
+    let kind = line.get(..2)?;
+    let segment = line.get(2..5)?.parse::<u8>().ok()?;
+    let id = line.get(5..8)?.parse::<u8>().ok()?;
+    let rest = line.get(8..)?;
+    match kind {
+        "-M" if rest == "!" => Some(PckMessage::Ack { segment, id }),
+        "-M" => Some(PckMessage::Nack {
+            segment,
+            id,
+            code: rest.parse().ok()?,
+        }),
+        ":M" if rest.starts_with("Rx") => {
+            let bits = rest.get(2..)?.parse::<u8>().ok()?;
+            let mut states = [false; 8];
+            for (i, state) in states.iter_mut().enumerate() {
+                *state = bits & (1 << i) != 0;
+            }
+            Some(PckMessage::Relays {
+                segment,
+                id,
+                states,
+            })
+        }
+        ":M" if rest.starts_with('A') => Some(PckMessage::Output {
+            segment,
+            id,
+            output: rest.get(1..2)?.parse().ok()?,
+            percent: rest.get(2..)?.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+fn target(command: &PckCommand) -> Target {
+    match command {
+        PckCommand::Output { target, .. } => *target,
+        PckCommand::Relays { target, .. } => *target,
+        PckCommand::Shutter { target, .. } => *target,
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => f.write_str(&e.to_string()),
+            Error::Auth => f.write_str("PCHK authentication failed"),
+            Error::Nack(code) => write!(f, "command rejected by module with code {}", code),
+            Error::Timeout => f.write_str("no response from PCHK"),
+            Error::InvalidCommand(e) => write!(f, "invalid command: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Minimal stand-in for PCHK: authenticates one client and acknowledges
+    /// every module command after reporting the new output state.
+    pub(crate) fn spawn_fake_pchk(password: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            writer
+                .write_all(b"LCN-PCHK 2.8 test double\nUsername:")
+                .unwrap();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(b"Password:").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line.trim_end() != password {
+                writer.write_all(b"Authentification failed.\n").unwrap();
+                return;
+            }
+            writer.write_all(b"OK\n").unwrap();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let address = line.get(2..8).unwrap_or("000000").to_owned();
+                if line.starts_with(">M") {
+                    writeln!(writer, ":M{}A1050", address).unwrap();
+                    writeln!(writer, "-M{}!", address).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_encode_commands() {
+        let module = Target::Module { segment: 0, id: 5 };
+        let output = PckCommand::Output {
+            target: module,
+            output: 1,
+            percent: 50,
+            ramp: 0,
+        };
+        assert_eq!(encode(&output).unwrap(), ">M000005!A1DI050000");
+
+        let relays = PckCommand::Relays {
+            target: Target::Group { segment: 0, id: 3 },
+            states: vec![RelayState::On, RelayState::Off, RelayState::Toggle],
+        };
+        assert_eq!(encode(&relays).unwrap(), ">G000003.R810U-----");
+
+        let shutter = PckCommand::Shutter {
+            target: module,
+            motor: 2,
+            movement: ShutterMove::Down,
+        };
+        assert_eq!(encode(&shutter).unwrap(), ">M000005!R8--11----");
+
+        let invalid = PckCommand::Output {
+            target: module,
+            output: 1,
+            percent: 150,
+            ramp: 0,
+        };
+        assert!(encode(&invalid).is_err());
+        let invalid = PckCommand::Output {
+            target: module,
+            output: 1,
+            percent: 50,
+            ramp: 251,
+        };
+        assert!(encode(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        assert_eq!(
+            parse_message("-M000005!"),
+            PckMessage::Ack { segment: 0, id: 5 }
+        );
+        assert_eq!(
+            parse_message("-M000005005"),
+            PckMessage::Nack {
+                segment: 0,
+                id: 5,
+                code: 5
+            }
+        );
+        assert_eq!(
+            parse_message(":M000005A2100"),
+            PckMessage::Output {
+                segment: 0,
+                id: 5,
+                output: 2,
+                percent: 100
+            }
+        );
+        let mut states = [false; 8];
+        states[0] = true;
+        states[2] = true;
+        assert_eq!(
+            parse_message(":M000005Rx005"),
+            PckMessage::Relays {
+                segment: 0,
+                id: 5,
+                states
+            }
+        );
+        assert_eq!(
+            parse_message("$io:#LCN:connected"),
+            PckMessage::Other {
+                text: "$io:#LCN:connected".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_execute_against_fake_pchk() {
+        let addr = spawn_fake_pchk("secret");
+        let mut client = PckClient::new(addr, "user".to_owned(), "secret".to_owned());
+        let command = PckCommand::Output {
+            target: Target::Module { segment: 0, id: 7 },
+            output: 1,
+            percent: 50,
+            ramp: 0,
+        };
+        let messages = client.execute(&command).unwrap();
+        assert_eq!(
+            messages,
+            vec![PckMessage::Output {
+                segment: 0,
+                id: 7,
+                output: 1,
+                percent: 50
+            }]
+        );
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let addr = spawn_fake_pchk("secret");
+        let mut client = PckClient::new(addr, "user".to_owned(), "wrong".to_owned());
+        assert!(matches!(client.connect(), Err(Error::Auth)));
+    }
+}
@@ -1,5 +1,11 @@
-use super::components::{LcnCommand, Schedule};
+use super::backup::{ExportedTask, ImportReport, ImportRequest};
+use super::components::{LcnCommand, Metadata, Schedule};
+use super::ics::CalendarTask;
+use super::systems::conflict_detector::Conflict;
+use super::systems::file_tasks::SyncReport;
+use super::systems::simulation::SimulatedRun;
 use super::systems::status_reporter::TaskStatus;
+use super::tasks_file::FileTask;
 use lame_ecs::Entity;
 use rocket::tokio::sync::{mpsc, oneshot};
 use serde::{Deserialize, Serialize};
@@ -8,20 +14,100 @@ use serde::{Deserialize, Serialize};
 pub enum Request {
     NewTask((oneshot::Sender<Response>, TaskRequest)),
     RemoveTask((oneshot::Sender<Response>, Entity)),
-    GetStatus(oneshot::Sender<Response>),
+    GetStatus((oneshot::Sender<Response>, StatusOrder)),
+    StartVacation((oneshot::Sender<Response>, VacationRequest)),
+    StopVacation(oneshot::Sender<Response>),
+    Simulate((oneshot::Sender<Response>, u32)),
+    ExecuteCommand((oneshot::Sender<Response>, LcnCommand)),
+    SetTaskEnabled((oneshot::Sender<Response>, (Entity, bool))),
+    ExecuteScene((oneshot::Sender<Response>, Vec<LcnCommand>)),
+    SetAlarm((oneshot::Sender<Response>, i64)),
+    ExportSchedule(oneshot::Sender<Response>),
+    TagAction((oneshot::Sender<Response>, (String, TagAction))),
+    ExportTasks(oneshot::Sender<Response>),
+    ImportTasks((oneshot::Sender<Response>, ImportRequest)),
+    SyncFileTasks((oneshot::Sender<Response>, Vec<FileTask>)),
+    GetConflicts((oneshot::Sender<Response>, Option<u32>)),
 }
 
 #[derive(Debug)]
 pub enum Response {
-    NewTask(Entity),
-    RemoveTask(bool),
+    /// The new task with warnings about conflicts with other tasks.
+    NewTask(Result<(Entity, Vec<String>), String>),
+    RemoveTask(Result<(), String>),
     GetStatus(Vec<TaskStatus>),
+    StartVacation(Vec<Entity>),
+    StopVacation(usize),
+    Simulate(Vec<SimulatedRun>),
+    ExecuteCommand(Entity),
+    SetTaskEnabled(bool),
+    ExecuteScene(Vec<Entity>),
+    SetAlarm(usize),
+    ExportSchedule(Vec<CalendarTask>),
+    TagAction(Vec<Entity>),
+    ExportTasks(Vec<ExportedTask>),
+    ImportTasks(Result<ImportReport, String>),
+    SyncFileTasks(Result<SyncReport, String>),
+    GetConflicts(Vec<Conflict>),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TaskRequest {
     pub schedule: Schedule,
     pub cmd: LcnCommand,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusOrder {
+    Id,
+    NextRun,
+}
+
+/// What to do with all tasks carrying a tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagAction {
+    Pause,
+    Resume,
+    Remove,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AlarmRequest {
+    pub time: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VacationRequest {
+    #[serde(default)]
+    pub windows: Vec<VacationWindow>,
+    #[serde(default)]
+    pub replay_history: bool,
+    #[serde(default = "default_replay_variation")]
+    pub replay_variation_min: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VacationWindow {
+    pub on_cmd: LcnCommand,
+    pub off_cmd: Option<LcnCommand>,
+    pub start_hour: i8,
+    pub start_min: i8,
+    pub end_hour: i8,
+    pub end_min: i8,
+    #[serde(default = "all_weekdays")]
+    pub weekdays: [bool; 7],
+}
+
+fn default_replay_variation() -> u16 {
+    20
+}
+
+fn all_weekdays() -> [bool; 7] {
+    [true; 7]
 }
 
 pub fn make_request(
@@ -39,7 +125,8 @@ pub fn make_request(
 
 #[derive(Debug)]
 pub enum RequestError {
-    Send(mpsc::error::SendError<Request>),
+    // the undelivered request is dropped, nobody ever looks at it again
+    Send(mpsc::error::SendError<()>),
     Recv(oneshot::error::TryRecvError),
 }
 
@@ -53,8 +140,8 @@ impl std::fmt::Display for RequestError {
 }
 
 impl From<mpsc::error::SendError<Request>> for RequestError {
-    fn from(e: mpsc::error::SendError<Request>) -> Self {
-        RequestError::Send(e)
+    fn from(_: mpsc::error::SendError<Request>) -> Self {
+        RequestError::Send(mpsc::error::SendError(()))
     }
 }
 
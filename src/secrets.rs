@@ -0,0 +1,207 @@
+use super::config::SecretsConfig;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::Write;
+
+pub const PASSPHRASE_ENV: &str = "HOME_AUTOMATION_PASSPHRASE";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+const VERSION: u32 = 1;
+
+/// Encrypts files holding secrets with a key read from the key file or
+/// derived from the passphrase in `HOME_AUTOMATION_PASSPHRASE`. Without
+/// either files are written in plain text.
+#[derive(Debug)]
+pub struct Secrets {
+    source: KeySource,
+}
+
+#[derive(Debug)]
+enum KeySource {
+    File([u8; KEY_LEN]),
+    Passphrase(String),
+    None,
+}
+
+/// The file format, `salt` is only set for passphrase derived keys.
+#[derive(Debug, Deserialize, Serialize)]
+struct Sealed {
+    version: u32,
+    #[serde(default)]
+    salt: Option<String>,
+    nonce: String,
+    data: String,
+}
+
+impl Secrets {
+    pub fn load(config: &SecretsConfig) -> Result<Secrets, String> {
+        if let Some(path) = &config.key_file {
+            let content =
+                std::fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+            return Ok(Secrets {
+                source: KeySource::File(parse_key(&content)?),
+            });
+        }
+        let source = match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) if !passphrase.is_empty() => KeySource::Passphrase(passphrase),
+            _ => KeySource::None,
+        };
+        Ok(Secrets { source })
+    }
+
+    pub fn is_encrypting(&self) -> bool {
+        !matches!(self.source, KeySource::None)
+    }
+
+    /// Returns the file content holding `plaintext`, with a fresh nonce and
+    /// salt on every call.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let salt = match self.source {
+            KeySource::None => return Ok(plaintext.to_vec()),
+            KeySource::File(_) => None,
+            KeySource::Passphrase(_) => Some(rand::thread_rng().gen::<[u8; SALT_LEN]>()),
+        };
+        let key = self.key(salt.as_ref().map(|s| &s[..]))?;
+        let nonce = rand::thread_rng().gen::<[u8; NONCE_LEN]>();
+        let data = cipher(&key)
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| "encryption failed".to_owned())?;
+        let sealed = Sealed {
+            version: VERSION,
+            salt: salt.map(hex::encode),
+            nonce: hex::encode(nonce),
+            data: hex::encode(data),
+        };
+        serde_json::to_vec(&sealed).map_err(|e| e.to_string())
+    }
+
+    /// Files written before encryption was configured are returned as they
+    /// are, see `is_sealed`.
+    pub fn open(&self, content: &[u8]) -> Result<Vec<u8>, String> {
+        let sealed: Sealed = match serde_json::from_slice(content) {
+            Ok(sealed) => sealed,
+            Err(_) => return Ok(content.to_vec()),
+        };
+        if sealed.version != VERSION {
+            return Err(format!("unsupported version {}", sealed.version));
+        }
+        let salt = sealed
+            .salt
+            .map(hex::decode)
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let nonce = hex::decode(&sealed.nonce).map_err(|e| e.to_string())?;
+        let data = hex::decode(&sealed.data).map_err(|e| e.to_string())?;
+        if nonce.len() != NONCE_LEN {
+            return Err("invalid nonce".to_owned());
+        }
+        let key = self.key(salt.as_deref())?;
+        cipher(&key)
+            .decrypt(Nonce::from_slice(&nonce), data.as_ref())
+            .map_err(|_| "wrong key or corrupted file".to_owned())
+    }
+
+    fn key(&self, salt: Option<&[u8]>) -> Result<[u8; KEY_LEN], String> {
+        match (&self.source, salt) {
+            (KeySource::File(key), None) => Ok(*key),
+            (KeySource::Passphrase(passphrase), Some(salt)) => {
+                let mut key = [0; KEY_LEN];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| e.to_string())?;
+                Ok(key)
+            }
+            (KeySource::File(_), Some(_)) => Err(format!(
+                "encrypted with a passphrase, set {}",
+                PASSPHRASE_ENV
+            )),
+            (KeySource::Passphrase(_), None) => {
+                Err("encrypted with a key file, set secrets.key_file".to_owned())
+            }
+            (KeySource::None, _) => Err("encrypted, but no key is configured".to_owned()),
+        }
+    }
+}
+
+pub fn is_sealed(content: &[u8]) -> bool {
+    serde_json::from_slice::<Sealed>(content).is_ok()
+}
+
+/// Writes a file only its owner may read.
+pub fn write_private(path: &str, content: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // the mode only applies to new files
+        if let Ok(metadata) = std::fs::metadata(path) {
+            let mut permissions = metadata.permissions();
+            permissions.set_mode(0o600);
+            std::fs::set_permissions(path, permissions)?;
+        }
+    }
+    options.open(path)?.write_all(content)
+}
+
+/// Key files hold the key as 64 hex digits or as 32 raw bytes.
+fn parse_key(content: &[u8]) -> Result<[u8; KEY_LEN], String> {
+    let text = String::from_utf8_lossy(content);
+    let key = match hex::decode(text.trim()) {
+        Ok(key) => key,
+        Err(_) => content.to_vec(),
+    };
+    key.try_into()
+        .map_err(|_| format!("key file must hold {} bytes", KEY_LEN))
+}
+
+fn cipher(key: &[u8; KEY_LEN]) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let plaintext = br#"{"addr":"192.168.0.10","proj":"home","cookie":"LCN-GVS-Auth=1"}"#;
+        let with_file = Secrets {
+            source: KeySource::File([7; KEY_LEN]),
+        };
+        let sealed = with_file.seal(plaintext).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!String::from_utf8_lossy(&sealed).contains("LCN-GVS-Auth"));
+        assert_eq!(with_file.open(&sealed).unwrap(), plaintext.to_vec());
+
+        let other_key = Secrets {
+            source: KeySource::File([8; KEY_LEN]),
+        };
+        assert!(other_key.open(&sealed).is_err());
+
+        let with_passphrase = Secrets {
+            source: KeySource::Passphrase("open sesame".to_owned()),
+        };
+        let sealed = with_passphrase.seal(plaintext).unwrap();
+        assert_eq!(with_passphrase.open(&sealed).unwrap(), plaintext.to_vec());
+        assert!(with_file.open(&sealed).is_err());
+
+        // files from before encryption was enabled
+        assert!(!is_sealed(plaintext));
+        assert_eq!(with_file.open(plaintext).unwrap(), plaintext.to_vec());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex_key = format!("{}\n", "ab".repeat(KEY_LEN));
+        assert_eq!(parse_key(hex_key.as_bytes()).unwrap(), [0xab; KEY_LEN]);
+        assert_eq!(parse_key(&[1; KEY_LEN]).unwrap(), [1; KEY_LEN]);
+        assert!(parse_key(b"too short").is_err());
+    }
+}
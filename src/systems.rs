@@ -1,4 +1,8 @@
+pub mod conflict_detector;
+pub mod file_tasks;
 pub mod lcn_command_executor;
+pub mod presence_simulator;
 pub mod request_processor;
 pub mod scheduler;
+pub mod simulation;
 pub mod status_reporter;
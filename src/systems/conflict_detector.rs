@@ -0,0 +1,160 @@
+use super::super::components::LcnCommand;
+use super::super::config::{self, CommandConfig};
+use super::simulation::{self, SimulatedRun};
+use chrono::Local;
+use lame_ecs::{component_iter, Entity, World};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Two runs of different tasks firing opposing commands within the window.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Conflict {
+    pub first: SimulatedRun,
+    pub second: SimulatedRun,
+    pub seconds_apart: i64,
+}
+
+/// Simulates the next `days` and pairs up the opposing runs. Jittered tasks
+/// are checked at one random sample of their times.
+pub fn find_conflicts(
+    world: &World,
+    catalog: &[CommandConfig],
+    from: chrono::DateTime<Local>,
+    days: u32,
+    window_sec: i64,
+    rng: &mut impl Rng,
+) -> Vec<Conflict> {
+    let runs = simulation::simulate(world, from, days, rng);
+    pair_up(catalog, &runs, window_sec)
+}
+
+/// The conflicts of a single task. Only the tasks sending a command that
+/// opposes its own are simulated along with it.
+pub fn find_task_conflicts(
+    world: &World,
+    catalog: &[CommandConfig],
+    task: Entity,
+    from: chrono::DateTime<Local>,
+    days: u32,
+    window_sec: i64,
+    rng: &mut impl Rng,
+) -> Vec<Conflict> {
+    let cmd = match world.get_component::<LcnCommand>(task) {
+        Some(cmd) => cmd.clone(),
+        None => return Vec::new(),
+    };
+    let involved: HashSet<i64> = component_iter!(world, LcnCommand)
+        .filter(|(other, entity)| {
+            entity.id() == task.id() || config::opposing(catalog, other, &cmd)
+        })
+        .map(|(_, entity)| entity.id())
+        .collect();
+    if involved.len() < 2 {
+        return Vec::new();
+    }
+    let runs = simulation::simulate_tasks(world, from, days, rng, |entity| {
+        involved.contains(&entity.id())
+    });
+    pair_up(catalog, &runs, window_sec)
+        .into_iter()
+        .filter(|c| c.first.task_id == task.id() || c.second.task_id == task.id())
+        .collect()
+}
+
+fn pair_up(catalog: &[CommandConfig], runs: &[SimulatedRun], window_sec: i64) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+    for (i, first) in runs.iter().enumerate() {
+        let close = runs[i + 1..]
+            .iter()
+            .take_while(|second| second.timestamp - first.timestamp <= window_sec);
+        for second in close {
+            if first.task_id != second.task_id && config::opposing(catalog, &first.cmd, &second.cmd)
+            {
+                conflicts.push(Conflict {
+                    first: first.clone(),
+                    second: second.clone(),
+                    seconds_apart: second.timestamp - first.timestamp,
+                });
+            }
+        }
+    }
+    conflicts
+}
+
+pub fn describe(catalog: &[CommandConfig], conflict: &Conflict) -> String {
+    let run = |run: &SimulatedRun| {
+        format!(
+            "task {} ({}) at {}",
+            run.task_id,
+            config::command_name(catalog, &run.cmd),
+            run.time
+        )
+    };
+    format!(
+        "{} conflicts with {}",
+        run(&conflict.second),
+        run(&conflict.first)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::components::*;
+    use super::*;
+    use chrono::TimeZone;
+    use lame_ecs::{create_world, Entity};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn new_task(world: &mut World, hour: i8, min: i8, cmd_id: i32) -> Entity {
+        let entity = world.new_entity();
+        let schedule = Schedule {
+            hour,
+            min,
+            weekdays: [true; 7],
+            ..Default::default()
+        };
+        world.add_component(entity, schedule);
+        world.add_component(entity, ActivationState::ToBeScheduled);
+        world.add_component(entity, LcnCommand::new(cmd_id));
+        entity
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let catalog = config::Config::default().commands();
+        let up = new_task(&mut world, 7, 0, 1632);
+        let down = new_task(&mut world, 7, 1, 1633);
+        new_task(&mut world, 7, 0, 1623);
+        new_task(&mut world, 20, 0, 1633);
+
+        let from = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let conflicts = find_conflicts(&world, &catalog, from, 3, 60, &mut rng);
+        assert_eq!(conflicts.len(), 3);
+        assert!(conflicts.iter().all(|c| c.first.task_id == up.id()
+            && c.second.task_id == down.id()
+            && c.seconds_apart == 60));
+
+        assert!(find_conflicts(&world, &catalog, from, 3, 59, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_find_task_conflicts() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let catalog = config::Config::default().commands();
+        let up = new_task(&mut world, 7, 0, 1632);
+        let down = new_task(&mut world, 7, 1, 1633);
+        let lamp = new_task(&mut world, 7, 0, 1623);
+
+        let from = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let conflicts = find_task_conflicts(&world, &catalog, down, from, 3, 60, &mut rng);
+        assert_eq!(conflicts.len(), 3);
+        assert!(conflicts
+            .iter()
+            .all(|c| c.first.task_id == up.id() && c.second.task_id == down.id()));
+        assert!(find_task_conflicts(&world, &catalog, lamp, from, 3, 60, &mut rng).is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use super::super::backup::ExportedTask;
+use super::super::components::*;
+use super::super::requests::TaskRequest;
+use super::super::tasks_file::FileTask;
+use super::request_processor::{create_lcn_task, exported_task};
+use lame_ecs::{component_iter, Entity, World};
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub added: Vec<Entity>,
+    pub updated: Vec<Entity>,
+    pub removed: Vec<Entity>,
+}
+
+/// Makes the file managed tasks match the tasks file: tasks with a new key
+/// are created, changed ones updated in place and rescheduled, the ones no
+/// longer in the file removed.
+pub fn sync(world: &mut World, tasks: Vec<FileTask>) -> SyncReport {
+    let mut existing: HashMap<String, Entity> = component_iter!(world, FileManaged)
+        .map(|(file, entity)| (file.key.clone(), *entity))
+        .collect();
+    let mut report = SyncReport::default();
+    for FileTask { key, task } in tasks {
+        let entity = match existing.remove(&key) {
+            Some(entity) => entity,
+            None => {
+                let entity = create_task(world, key, task);
+                report.added.push(entity);
+                continue;
+            }
+        };
+        if exported_task(world, entity).is_some_and(|current| same(&current, &task)) {
+            continue;
+        }
+        println!("file task {} changed", key);
+        *world.get_component::<Schedule>(entity).unwrap() = task.schedule;
+        *world.get_component::<LcnCommand>(entity).unwrap() = task.cmd;
+        *world.get_component::<ActivationState>(entity).unwrap() = initial_state(task.enabled);
+        match world.get_component::<Metadata>(entity) {
+            Some(metadata) => *metadata = task.metadata,
+            None => world.add_component(entity, task.metadata),
+        }
+        report.updated.push(entity);
+    }
+    for (key, entity) in existing {
+        println!("file task {} removed", key);
+        world.remove_entity(entity);
+        report.removed.push(entity);
+    }
+    report
+}
+
+fn create_task(world: &mut World, key: String, task: ExportedTask) -> Entity {
+    let request = TaskRequest {
+        schedule: task.schedule,
+        cmd: task.cmd,
+        metadata: task.metadata,
+    };
+    let entity = create_lcn_task(world, request);
+    world.add_component(entity, FileManaged { key });
+    *world.get_component::<ActivationState>(entity).unwrap() = initial_state(task.enabled);
+    entity
+}
+
+fn initial_state(enabled: bool) -> ActivationState {
+    match enabled {
+        true => ActivationState::ToBeScheduled,
+        false => ActivationState::Paused,
+    }
+}
+
+fn same(a: &ExportedTask, b: &ExportedTask) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lame_ecs::create_world;
+
+    fn file_task(key: &str, hour: i8) -> FileTask {
+        FileTask {
+            key: key.to_owned(),
+            task: ExportedTask {
+                schedule: Schedule {
+                    hour,
+                    weekdays: [true; 7],
+                    ..Default::default()
+                },
+                cmd: LcnCommand::new(1632),
+                metadata: Metadata::default(),
+                enabled: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut world = create_world!();
+        let report = sync(&mut world, vec![file_task("up", 7), file_task("down", 21)]);
+        assert_eq!(report.added.len(), 2);
+        let (up, down) = (report.added[0], report.added[1]);
+        *world.get_component::<ActivationState>(up).unwrap() = ActivationState::Scheduled(0);
+
+        // unchanged tasks keep their state
+        let report = sync(&mut world, vec![file_task("up", 7), file_task("down", 21)]);
+        assert!(report.added.is_empty() && report.updated.is_empty());
+        let state = world.get_component::<ActivationState>(up).unwrap();
+        assert_eq!(*state, ActivationState::Scheduled(0));
+
+        let mut paused = file_task("up", 8);
+        paused.task.enabled = false;
+        let report = sync(&mut world, vec![paused, file_task("night", 23)]);
+        let ids = |entities: &[Entity]| entities.iter().map(Entity::id).collect::<Vec<_>>();
+        assert_eq!(ids(&report.updated), vec![up.id()]);
+        assert_eq!(ids(&report.removed), vec![down.id()]);
+        assert_eq!(report.added.len(), 1);
+        assert!(!world.is_alive(down));
+        assert_eq!(world.get_component::<Schedule>(up).unwrap().hour, 8);
+        let state = world.get_component::<ActivationState>(up).unwrap();
+        assert_eq!(*state, ActivationState::Paused);
+    }
+}
@@ -1,58 +1,311 @@
+use super::super::clock::Clock;
 use super::super::components::*;
+use super::super::events::{Event, EventBus};
+use super::super::guards::{GuardDecision, Guards};
+use super::super::history::{History, HistoryEntry};
 use super::super::lcn;
-use lame_ecs::{component_iter, component_iter_mut, World};
-use lcn::LcnClient;
+use super::super::pck;
+use lame_ecs::{component_iter, component_iter_mut, Entity, World};
+use pck::PckClient;
 use reqwest::header;
 use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-pub fn process(world: &mut World, client: &LcnClient) {
-    if !has_command_to_execute(world) {
-        return;
+const RETRY_BACKOFF_SEC: i64 = 5;
+const MAX_RETRY_BACKOFF_SEC: i64 = 300;
+// gives up after about a quarter of an hour, later runs would come as a surprise
+const MAX_RETRIES: u32 = 8;
+
+pub fn process(
+    world: &mut World,
+    clients: &mut HashMap<String, lcn::Client>,
+    history: &mut History,
+    guards: &mut Guards,
+    clock: &dyn Clock,
+    events: &EventBus,
+) {
+    let retries = component_iter!(world, LastExecution)
+        .map(|(last, entity)| (entity.id(), last.retries))
+        .collect();
+    let mut log = ExecutionLog {
+        history,
+        guards,
+        clock,
+        events,
+        retries,
+        results: Vec::new(),
+    };
+    apply_guards(world, &mut log);
+    for installation in installations_to_execute(world) {
+        let client = match clients.get_mut(&installation) {
+            Some(lcn::Client::Gvs(client)) => client,
+            Some(lcn::Client::Pck(client)) => {
+                execute_pck_commands(world, &installation, client, &mut log);
+                continue;
+            }
+            None => {
+                println!("executor: no lcn client for installation {}", installation);
+                drop_commands(world, &installation, &mut log);
+                continue;
+            }
+        };
+        let home = get_home_page(&client.http_client, &client.home_url);
+        let mdl = home.as_deref().and_then(parse_mdl);
+        println!("executor: {} mdl request result: {:?}", installation, mdl);
+        if mdl.is_none() {
+            // a reachable home page without mdl is the login page
+            if home.is_some() {
+                log.events.emit(Event::AuthLost {
+                    installation: installation.clone(),
+                });
+            }
+            retry_commands(world, &installation, &mut log);
+            continue;
+        }
+        execute_commands(
+            world,
+            &installation,
+            &client.http_client,
+            &client.command_url,
+            mdl.unwrap(),
+            &mut log,
+        );
+    }
+    update_last_executions(world, &log.results);
+    remove_immediate_commands(world);
+}
+
+/// Records execution results in the history and publishes them on the bus,
+/// `results` are applied to the tasks once the world is no longer borrowed.
+struct ExecutionLog<'a> {
+    history: &'a mut History,
+    guards: &'a mut Guards,
+    clock: &'a dyn Clock,
+    events: &'a EventBus,
+    retries: HashMap<i64, u32>,
+    results: Vec<(Entity, LastExecution)>,
+}
+
+impl ExecutionLog<'_> {
+    /// Failed commands are retried with a backoff doubling with every
+    /// retry. After `MAX_RETRIES` the run counts as failed and the task
+    /// waits for its next regular run.
+    fn retry(&self, entity: &Entity) -> ActivationState {
+        let retries = self.retries(entity);
+        if retries >= MAX_RETRIES {
+            println!(
+                "executor: task {} failed after {} retries",
+                entity.id(),
+                retries
+            );
+            return ActivationState::ToBeScheduled;
+        }
+        let backoff = RETRY_BACKOFF_SEC << retries.min(6);
+        let time = self.clock.now().timestamp() + backoff.min(MAX_RETRY_BACKOFF_SEC);
+        ActivationState::Scheduled(time)
+    }
+
+    fn retries(&self, entity: &Entity) -> u32 {
+        self.retries.get(&entity.id()).copied().unwrap_or(0)
+    }
+
+    /// Failed commands that were scheduled again will be retried.
+    fn record(
+        &mut self,
+        entity: &Entity,
+        cmd: &LcnCommand,
+        state: &ActivationState,
+        success: bool,
+    ) {
+        let time = self.clock.now().timestamp();
+        if success {
+            self.guards.sent(cmd, time);
+        }
+        let retrying = !success && matches!(state, ActivationState::Scheduled(_));
+        self.results.push((
+            *entity,
+            LastExecution {
+                time,
+                success,
+                failures: 0,
+                retries: if retrying {
+                    self.retries(entity) + 1
+                } else {
+                    0
+                },
+            },
+        ));
+        self.history.record(HistoryEntry {
+            time,
+            entity: entity.id(),
+            cmd: cmd.clone(),
+            success,
+            guard: None,
+        });
+        self.events.emit(Event::CommandExecuted {
+            task_id: entity.id(),
+            cmd: cmd.clone(),
+            success,
+            retrying,
+        });
+    }
+
+    fn guarded(&mut self, entity: &Entity, cmd: &LcnCommand, decision: GuardDecision) {
+        println!(
+            "executor: command {} of task {} held back: {:?}",
+            cmd.id,
+            entity.id(),
+            decision
+        );
+        self.history.record(HistoryEntry {
+            time: self.clock.now().timestamp(),
+            entity: entity.id(),
+            cmd: cmd.clone(),
+            success: false,
+            guard: Some(decision),
+        });
+    }
+}
+
+/// Holds back the commands the device guards object to before anything is
+/// sent. Deferred commands wait in the scheduled state, the others skip
+/// this run.
+fn apply_guards(world: &mut World, log: &mut ExecutionLog) {
+    let now = log.clock.now().timestamp();
+    let catch_ups: HashSet<i64> = component_iter!(world, CatchUp)
+        .map(|(_, entity)| entity.id())
+        .collect();
+    let waiting: HashMap<i64, Option<i64>> = component_iter!(world, ActivationState)
+        .filter_map(|(state, entity)| match state {
+            ActivationState::Scheduled(t) => Some((entity.id(), Some(*t))),
+            ActivationState::ReadyToRun => Some((entity.id(), None)),
+            _ => None,
+        })
+        .collect();
+    log.guards
+        .retain_deferred(|entity, until| match waiting.get(&entity.id()) {
+            Some(Some(t)) => *t == until,
+            Some(None) => true,
+            None => false,
+        });
+    let mut round = HashMap::new();
+    let mut superseded = Vec::new();
+    for (state, command, entity) in component_iter_mut!(world, ActivationState, LcnCommand) {
+        if *state != ActivationState::ReadyToRun {
+            continue;
+        }
+        for (older, cmd) in log.guards.supersede(entity, command) {
+            superseded.push((older, cmd, entity.id()));
+        }
+        let catch_up = catch_ups.contains(&entity.id());
+        let decision = match log.guards.check(entity, command, catch_up, now, &mut round) {
+            Some(decision) => decision,
+            None => continue,
+        };
+        *state = match decision {
+            GuardDecision::Deferred { until } => ActivationState::Scheduled(until),
+            _ => ActivationState::ToBeScheduled,
+        };
+        log.guarded(entity, command, decision);
+    }
+    for (entity, cmd, by) in superseded {
+        match world.get_component::<ActivationState>(entity) {
+            Some(state) if matches!(state, ActivationState::Scheduled(_)) => {
+                *state = ActivationState::ToBeScheduled
+            }
+            _ => continue,
+        }
+        log.guarded(&entity, &cmd, GuardDecision::Superseded { by });
+    }
+}
+
+fn update_last_executions(world: &mut World, results: &[(Entity, LastExecution)]) {
+    for (entity, result) in results {
+        let failures = match world.get_component::<LastExecution>(*entity) {
+            Some(last) => last.failures,
+            None => 0,
+        };
+        let last = LastExecution {
+            failures: if result.success { 0 } else { failures + 1 },
+            ..*result
+        };
+        match world.get_component::<LastExecution>(*entity) {
+            Some(previous) => *previous = last,
+            None => world.add_component(*entity, last),
+        }
     }
-    let mdl = get_mdl(&client.http_client, &client.home_url);
-    println!("executor: mdl request result: {:?}", mdl);
-    if mdl.is_none() {
-        return;
+}
+
+/// Commands without a schedule were requested for immediate execution and
+/// are done once they left the ready to run state.
+fn remove_immediate_commands(world: &mut World) {
+    let done: Vec<Entity> = component_iter!(world, ActivationState, LcnCommand)
+        .filter(|(state, _, _)| **state == ActivationState::ToBeScheduled)
+        .map(|(_, _, entity)| *entity)
+        .collect();
+    for entity in done {
+        if world.get_component::<Schedule>(entity).is_none() {
+            world.remove_entity(entity);
+            println!("executor: immediate command {} done", entity.id());
+        }
     }
-    execute_commands(
-        world,
-        &client.http_client,
-        &client.command_url,
-        mdl.unwrap(),
-    );
 }
 
-fn has_command_to_execute(world: &World) -> bool {
+fn installations_to_execute(world: &World) -> BTreeSet<String> {
     let range = component_iter!(world, ActivationState, LcnCommand);
-    for (state, _, _) in range {
+    let mut installations = BTreeSet::new();
+    for (state, command, _) in range {
         if *state == ActivationState::ReadyToRun {
-            return true;
+            installations.insert(command.installation.clone());
+        }
+    }
+    installations
+}
+
+fn drop_commands(world: &mut World, installation: &str, log: &mut ExecutionLog) {
+    let range = component_iter_mut!(world, ActivationState, LcnCommand);
+    for (state, command, entity) in range {
+        if *state != ActivationState::ReadyToRun || command.installation != installation {
+            continue;
+        }
+        *state = ActivationState::ToBeScheduled;
+        log.record(entity, command, state, false);
+    }
+}
+
+fn retry_commands(world: &mut World, installation: &str, log: &mut ExecutionLog) {
+    let range = component_iter_mut!(world, ActivationState, LcnCommand);
+    for (state, command, entity) in range {
+        if *state != ActivationState::ReadyToRun || command.installation != installation {
+            continue;
         }
+        *state = log.retry(entity);
+        log.record(entity, command, state, false);
     }
-    false
 }
 
-fn get_mdl(client: &reqwest::blocking::Client, home_url: &str) -> Option<i32> {
-    let res = client
+fn get_home_page(client: &reqwest::blocking::Client, home_url: &str) -> Option<String> {
+    client
         .get(home_url)
         .header(header::CONTENT_TYPE, "application/json")
         .send()
         .ok()?
         .text()
-        .ok()?;
-    parse_mdl(&res)
+        .ok()
 }
 
 fn execute_commands(
     world: &mut World,
+    installation: &str,
     client: &reqwest::blocking::Client,
     command_url: &str,
     mdl: i32,
+    log: &mut ExecutionLog,
 ) {
     let range = component_iter_mut!(world, ActivationState, LcnCommand);
 
-    for (state, command, _) in range {
-        if *state != ActivationState::ReadyToRun {
+    for (state, command, entity) in range {
+        if *state != ActivationState::ReadyToRun || command.installation != installation {
             continue;
         }
 
@@ -67,9 +320,11 @@ fn execute_commands(
             .header(header::CONTENT_TYPE, "application/json")
             .json(&r)
             .send();
-        if command_response.is_ok() {
-            *state = ActivationState::ToBeScheduled;
-        }
+        *state = match command_response.is_ok() {
+            true => ActivationState::ToBeScheduled,
+            false => log.retry(entity),
+        };
+        log.record(entity, command, state, command_response.is_ok());
         println!(
             "executor: command request succeeded: {}",
             command_response.is_ok()
@@ -81,13 +336,61 @@ fn execute_commands(
     }
 }
 
+fn execute_pck_commands(
+    world: &mut World,
+    installation: &str,
+    client: &mut PckClient,
+    log: &mut ExecutionLog,
+) {
+    let range = component_iter_mut!(world, ActivationState, LcnCommand);
+
+    for (state, command, entity) in range {
+        if *state != ActivationState::ReadyToRun || command.installation != installation {
+            continue;
+        }
+        let result = match &command.pck {
+            Some(pck_command) => client.execute(pck_command),
+            None => Err(pck::Error::InvalidCommand(format!(
+                "command {} has no pck description",
+                command.id
+            ))),
+        };
+        match &result {
+            Ok(messages) => {
+                for message in messages {
+                    println!("executor: pck status: {:?}", message);
+                    log.events.emit(Event::DeviceState {
+                        installation: installation.to_owned(),
+                        message: message.clone(),
+                    });
+                }
+                *state = ActivationState::ToBeScheduled;
+            }
+            Err(e @ pck::Error::InvalidCommand(_)) | Err(e @ pck::Error::Nack(_)) => {
+                println!("executor: pck command dropped: {}", e);
+                *state = ActivationState::ToBeScheduled;
+            }
+            Err(e) => {
+                println!("executor: pck command failed: {}", e);
+                if let pck::Error::Auth = e {
+                    log.events.emit(Event::AuthLost {
+                        installation: installation.to_owned(),
+                    });
+                }
+                *state = log.retry(entity);
+            }
+        }
+        log.record(entity, command, state, result.is_ok());
+    }
+}
+
 fn parse_mdl(html: &str) -> Option<i32> {
     let offset = 10;
     let i = html.find("mdl")? + offset;
     let mdl: String = html
         .get(i..)?
         .chars()
-        .take_while(|c| c.is_digit(10))
+        .take_while(|c| c.is_ascii_digit())
         .collect();
     mdl.parse::<i32>().ok()
 }
@@ -110,3 +413,330 @@ struct LcnCmdRequest {
     id: String,
     updatedIds: Vec<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::clock::ManualClock;
+    use super::super::super::components::lcn_command::DEFAULT_INSTALLATION;
+    use super::super::super::config::{Config, GuardConfig};
+    use super::super::scheduler;
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use lame_ecs::create_world;
+
+    #[test]
+    fn test_guards_hold_back_commands() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let now = clock.now().timestamp();
+        let mut history = History::default();
+        let config = GuardConfig {
+            supersede: false,
+            ..Default::default()
+        };
+        let mut guards = Guards::new(config, Config::default().commands());
+        let mut new_command = |id| {
+            let entity = world.new_entity();
+            world.add_component(entity, ActivationState::ReadyToRun);
+            world.add_component(entity, LcnCommand::new(id));
+            entity
+        };
+        let (down, twice, lamp) = (new_command(1633), new_command(1632), new_command(1623));
+        guards.sent(&LcnCommand::new(1632), now - 5);
+
+        // without a client for the installation the commands fail
+        process(
+            &mut world,
+            &mut HashMap::new(),
+            &mut history,
+            &mut guards,
+            &clock,
+            &EventBus::new(),
+        );
+        let decisions: Vec<(i64, Option<GuardDecision>)> = history
+            .since(0)
+            .map(|entry| (entry.entity, entry.guard))
+            .collect();
+        assert!(decisions.contains(&(down.id(), Some(GuardDecision::Deferred { until: now + 5 }))));
+        assert!(decisions.contains(&(twice.id(), Some(GuardDecision::Debounced))));
+        assert!(decisions.contains(&(lamp.id(), None)));
+        assert!(world.is_alive(down));
+        let state = world.get_component::<ActivationState>(down).unwrap();
+        assert_eq!(*state, ActivationState::Scheduled(now + 5));
+    }
+
+    #[test]
+    fn test_failed_commands_are_retried_with_backoff() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let now = clock.now().timestamp();
+        let mut history = History::default();
+        let mut guards = Guards::new(GuardConfig::default(), Vec::new());
+        // nothing listens on the port of a closed listener
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = PckClient::new(addr.to_string(), "user".to_owned(), "secret".to_owned());
+        let mut clients = HashMap::new();
+        clients.insert(DEFAULT_INSTALLATION.to_owned(), lcn::Client::Pck(client));
+        let entity = world.new_entity();
+        let mut cmd = LcnCommand::new(1623);
+        cmd.pck = Some(pck::PckCommand::Relays {
+            target: pck::Target::Module { segment: 0, id: 5 },
+            states: vec![pck::RelayState::On],
+        });
+        world.add_component(entity, cmd);
+        world.add_component(entity, ActivationState::ReadyToRun);
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        for backoff in [5, 10, 20] {
+            *world.get_component::<ActivationState>(entity).unwrap() = ActivationState::ReadyToRun;
+            process(
+                &mut world,
+                &mut clients,
+                &mut history,
+                &mut guards,
+                &clock,
+                &events,
+            );
+            let state = world.get_component::<ActivationState>(entity).unwrap();
+            assert_eq!(*state, ActivationState::Scheduled(now + backoff));
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(Event::CommandExecuted { retrying: true, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_catch_up_runs_are_spaced_out() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 18).and_hms(6, 40, 0));
+        let now = clock.now().timestamp();
+        let mut history = History::default();
+        let mut guards = Guards::new(GuardConfig::default(), Config::default().commands());
+        let task = world.new_entity();
+        let schedule = Schedule {
+            hour: 6,
+            min: 30,
+            weekdays: [true; 7],
+            misfire: schedule::Misfire::RunAll,
+            ..Default::default()
+        };
+        let missed = Local.ymd(2021, 6, 16).and_hms(6, 30, 0).timestamp();
+        world.add_component(task, schedule);
+        world.add_component(task, ActivationState::Scheduled(missed));
+        world.add_component(task, LcnCommand::new(1632));
+        let events = EventBus::new();
+        let mut rng = rand::thread_rng();
+        scheduler::process(&mut world, &clock, &mut rng, &events);
+
+        let mut decisions = Vec::new();
+        for _ in 0..3 {
+            process(
+                &mut world,
+                &mut HashMap::new(),
+                &mut history,
+                &mut guards,
+                &clock,
+                &events,
+            );
+            decisions.push(
+                history
+                    .since(clock.now().timestamp())
+                    .map(|entry| entry.guard)
+                    .collect::<Vec<_>>(),
+            );
+            clock.advance(chrono::Duration::seconds(10));
+            scheduler::process(&mut world, &clock, &mut rng, &events);
+        }
+        // one run every min_interval_sec, none of them debounced
+        let deferred = |until| Some(GuardDecision::Deferred { until });
+        assert_eq!(
+            decisions,
+            vec![
+                vec![deferred(now + 10), deferred(now + 10), None],
+                vec![deferred(now + 20), None],
+                vec![None],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands_are_routed_per_installation() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let now = clock.now().timestamp();
+        let mut history = History::default();
+        let mut guards = Guards::new(GuardConfig::default(), Vec::new());
+        let reachable = pck::tests::spawn_fake_pchk("secret");
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut clients = HashMap::new();
+        for (installation, addr) in [("home", reachable), ("garage", unreachable)] {
+            let client = PckClient::new(addr, "user".to_owned(), "secret".to_owned());
+            clients.insert(installation.to_owned(), lcn::Client::Pck(client));
+        }
+        let mut new_command = |installation: &str, id| {
+            let entity = world.new_entity();
+            let mut cmd = LcnCommand::new(1623);
+            cmd.installation = installation.to_owned();
+            cmd.pck = Some(pck::PckCommand::Relays {
+                target: pck::Target::Module { segment: 0, id },
+                states: vec![pck::RelayState::On],
+            });
+            world.add_component(entity, cmd);
+            world.add_component(entity, Schedule::default());
+            world.add_component(entity, ActivationState::ReadyToRun);
+            entity
+        };
+        // the shed has no client at all
+        let home = new_command("home", 5);
+        let garage = new_command("garage", 6);
+        let shed = new_command("shed", 7);
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        process(
+            &mut world,
+            &mut clients,
+            &mut history,
+            &mut guards,
+            &clock,
+            &events,
+        );
+        let expected = [
+            (home, ActivationState::ToBeScheduled, true),
+            (garage, ActivationState::Scheduled(now + 5), false),
+            (shed, ActivationState::ToBeScheduled, false),
+        ];
+        for (entity, state, success) in expected {
+            assert_eq!(
+                *world.get_component::<ActivationState>(entity).unwrap(),
+                state
+            );
+            assert_eq!(
+                world
+                    .get_component::<LastExecution>(entity)
+                    .unwrap()
+                    .success,
+                success
+            );
+        }
+
+        let mut device_states = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            if let Event::DeviceState { installation, .. } = event {
+                device_states.push(installation);
+            }
+        }
+        assert!(!device_states.is_empty());
+        assert!(device_states
+            .iter()
+            .all(|installation| installation == "home"));
+    }
+
+    #[test]
+    fn test_removed_deferred_task_does_not_debounce() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let now = clock.now().timestamp();
+        let mut history = History::default();
+        let mut guards = Guards::new(GuardConfig::default(), Config::default().commands());
+        guards.sent(&LcnCommand::new(1632), now - 5);
+        let new_command = |world: &mut World| {
+            let entity = world.new_entity();
+            world.add_component(entity, ActivationState::ReadyToRun);
+            world.add_component(entity, LcnCommand::new(1633));
+            entity
+        };
+        let events = EventBus::new();
+        let deferred = new_command(&mut world);
+        process(
+            &mut world,
+            &mut HashMap::new(),
+            &mut history,
+            &mut guards,
+            &clock,
+            &events,
+        );
+        let state = world.get_component::<ActivationState>(deferred).unwrap();
+        assert_eq!(*state, ActivationState::Scheduled(now + 5));
+
+        world.remove_entity(deferred);
+        clock.advance(chrono::Duration::seconds(60));
+        let again = new_command(&mut world);
+        process(
+            &mut world,
+            &mut HashMap::new(),
+            &mut history,
+            &mut guards,
+            &clock,
+            &events,
+        );
+        let decisions: Vec<(i64, Option<GuardDecision>)> = history
+            .since(clock.now().timestamp())
+            .map(|entry| (entry.entity, entry.guard))
+            .collect();
+        assert_eq!(decisions, vec![(again.id(), None)]);
+    }
+
+    #[test]
+    fn test_retries_give_up_and_wait_for_the_next_run() {
+        let mut world = create_world!();
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let mut history = History::default();
+        let mut guards = Guards::new(GuardConfig::default(), Vec::new());
+        let entity = world.new_entity();
+        world.add_component(entity, Schedule::default());
+        world.add_component(entity, LcnCommand::new(1623));
+        world.add_component(entity, ActivationState::ReadyToRun);
+        world.add_component(
+            entity,
+            LastExecution {
+                time: 0,
+                success: false,
+                failures: 20,
+                retries: MAX_RETRIES - 1,
+            },
+        );
+        let mut clients = HashMap::new();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = PckClient::new(addr.to_string(), "user".to_owned(), "secret".to_owned());
+        clients.insert(DEFAULT_INSTALLATION.to_owned(), lcn::Client::Pck(client));
+        world.get_component::<LcnCommand>(entity).unwrap().pck = Some(pck::PckCommand::Relays {
+            target: pck::Target::Module { segment: 0, id: 5 },
+            states: vec![pck::RelayState::On],
+        });
+
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        for retrying in [true, false] {
+            *world.get_component::<ActivationState>(entity).unwrap() = ActivationState::ReadyToRun;
+            process(
+                &mut world,
+                &mut clients,
+                &mut history,
+                &mut guards,
+                &clock,
+                &events,
+            );
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(Event::CommandExecuted { retrying: r, .. }) if r == retrying
+            ));
+        }
+        let state = world.get_component::<ActivationState>(entity).unwrap();
+        assert_eq!(*state, ActivationState::ToBeScheduled);
+        let last = world.get_component::<LastExecution>(entity).unwrap();
+        assert_eq!((last.failures, last.retries), (22, 0));
+    }
+}
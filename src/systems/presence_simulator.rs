@@ -0,0 +1,241 @@
+use super::super::components::*;
+use super::super::history::History;
+use super::super::requests::{TaskRequest, VacationRequest, VacationWindow};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use lame_ecs::{component_iter, Entity, World};
+
+const MINUTES_PER_DAY: i32 = 24 * 60;
+const SECONDS_PER_WEEK: i64 = 7 * 24 * 3600;
+const VACATION_TAG: &str = "vacation";
+
+pub fn start(
+    world: &mut World,
+    history: &History,
+    request: VacationRequest,
+    now: &chrono::DateTime<Local>,
+) -> Vec<Entity> {
+    let removed = stop(world).len();
+    if removed > 0 {
+        println!(
+            "presence_simulator: {} previous vacation tasks replaced",
+            removed
+        );
+    }
+    let mut tasks = Vec::new();
+    for window in &request.windows {
+        let (start, end) = window_minutes(window);
+        // the jitter picks new times for every evening, the light goes on in
+        // the first half of the window and off in the second
+        let jitter = (end - start) / 4;
+        let on = new_task(
+            world,
+            window.weekdays,
+            start + jitter,
+            jitter,
+            &window.on_cmd,
+        );
+        tasks.push(on);
+        if let Some(cmd) = &window.off_cmd {
+            tasks.push(new_task(world, window.weekdays, end - jitter, jitter, cmd));
+        }
+    }
+    if request.replay_history {
+        let variation = request.replay_variation_min as i32;
+        for entry in history.since(now.timestamp() - SECONDS_PER_WEEK) {
+            if !entry.success {
+                continue;
+            }
+            let time = Local.timestamp(entry.time, 0);
+            let mut weekdays = [false; 7];
+            weekdays[time.weekday().num_days_from_monday() as usize] = true;
+            let minute = time.hour() as i32 * 60 + time.minute() as i32;
+            tasks.push(new_task(world, weekdays, minute, variation, &entry.cmd));
+        }
+    }
+    println!("presence_simulator: {} vacation tasks created", tasks.len());
+    tasks
+}
+
+pub fn stop(world: &mut World) -> Vec<Entity> {
+    let entities: Vec<Entity> = component_iter!(world, Vacation)
+        .map(|(_, entity)| *entity)
+        .collect();
+    for entity in &entities {
+        world.remove_entity(*entity);
+    }
+    entities
+}
+
+/// Windows ending at or before their start end on the next day.
+fn window_minutes(window: &VacationWindow) -> (i32, i32) {
+    let start = window.start_hour as i32 * 60 + window.start_min as i32;
+    let mut end = window.end_hour as i32 * 60 + window.end_min as i32;
+    if end <= start {
+        end += MINUTES_PER_DAY;
+    }
+    (start, end)
+}
+
+/// `minute` counts from the start of the given weekdays and moves the task
+/// to the following days if it is past midnight.
+fn new_task(
+    world: &mut World,
+    weekdays: [bool; 7],
+    minute: i32,
+    jitter_min: i32,
+    cmd: &LcnCommand,
+) -> Entity {
+    let days = minute.div_euclid(MINUTES_PER_DAY);
+    let day_minute = minute.rem_euclid(MINUTES_PER_DAY);
+    let mut schedule = Schedule {
+        hour: (day_minute / 60) as i8,
+        min: (day_minute % 60) as i8,
+        jitter_min: jitter_min as u16,
+        ..Default::default()
+    };
+    for (weekday, active) in weekdays.iter().enumerate() {
+        if *active {
+            schedule.weekdays[(weekday as i32 + days).rem_euclid(7) as usize] = true;
+        }
+    }
+    let entity = super::request_processor::create_lcn_task(
+        world,
+        TaskRequest {
+            schedule,
+            cmd: cmd.clone(),
+            metadata: Metadata {
+                tags: std::iter::once(VACATION_TAG.to_owned()).collect(),
+                ..Default::default()
+            },
+        },
+    );
+    world.add_component(entity, Vacation);
+    entity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::history::HistoryEntry;
+    use super::*;
+    use lame_ecs::create_world;
+
+    fn evening_window() -> VacationWindow {
+        VacationWindow {
+            on_cmd: LcnCommand::new(1681),
+            off_cmd: Some(LcnCommand::new(1682)),
+            start_hour: 19,
+            start_min: 0,
+            end_hour: 23,
+            end_min: 0,
+            weekdays: [true; 7],
+        }
+    }
+
+    fn request(windows: Vec<VacationWindow>, replay_history: bool) -> VacationRequest {
+        VacationRequest {
+            windows,
+            replay_history,
+            replay_variation_min: 20,
+        }
+    }
+
+    fn schedules(world: &World) -> Vec<(Schedule, i32)> {
+        let mut schedules: Vec<(Schedule, i32)> = component_iter!(world, Schedule, LcnCommand)
+            .map(|(schedule, cmd, _)| (schedule.clone(), cmd.id))
+            .collect();
+        schedules.sort_by_key(|(_, id)| *id);
+        schedules
+    }
+
+    #[test]
+    fn test_window_tasks() {
+        let mut world = create_world!();
+        let now = Local::now();
+        let request = request(vec![evening_window()], false);
+        let tasks = start(&mut world, &History::default(), request, &now);
+        assert!(tasks.iter().all(|t| world
+            .get_component::<Metadata>(*t)
+            .is_some_and(|m| m.tags.contains(VACATION_TAG))));
+        assert_eq!(tasks.len(), 2);
+
+        // the times vary from day to day through the jitter
+        let schedules = schedules(&world);
+        let (on, off) = (&schedules[0].0, &schedules[1].0);
+        assert_eq!((on.hour, on.min, on.jitter_min), (20, 0, 60));
+        assert_eq!((off.hour, off.min, off.jitter_min), (22, 0, 60));
+        assert_eq!(on.weekdays, [true; 7]);
+        assert_eq!(off.weekdays, [true; 7]);
+    }
+
+    #[test]
+    fn test_window_across_midnight() {
+        let mut world = create_world!();
+        let mut window = evening_window();
+        window.start_hour = 23;
+        window.end_hour = 1;
+        window.weekdays = [false, false, false, false, false, false, true];
+        start(
+            &mut world,
+            &History::default(),
+            request(vec![window], false),
+            &Local::now(),
+        );
+
+        let schedules = schedules(&world);
+        let (on, off) = (&schedules[0].0, &schedules[1].0);
+        assert_eq!((on.hour, on.min, on.jitter_min), (23, 30, 30));
+        assert!(on.weekdays[6]);
+        // Sunday's window ends on Monday
+        assert_eq!((off.hour, off.min, off.jitter_min), (0, 30, 30));
+        assert_eq!(
+            off.weekdays,
+            [true, false, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_replay_history() {
+        let mut world = create_world!();
+        let now = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let mut history = History::default();
+        for (days_ago, success) in [(2, true), (3, false), (9, true)] {
+            history.record(HistoryEntry {
+                time: (now - chrono::Duration::days(days_ago)).timestamp(),
+                entity: 0,
+                cmd: LcnCommand::new(1632),
+                success,
+                guard: None,
+            });
+        }
+        let tasks = start(&mut world, &history, request(Vec::new(), true), &now);
+        assert_eq!(tasks.len(), 1);
+
+        let schedule = world.get_component::<Schedule>(tasks[0]).unwrap();
+        assert_eq!(
+            (schedule.hour, schedule.min, schedule.jitter_min),
+            (12, 0, 20)
+        );
+        assert_eq!(
+            schedule.weekdays,
+            [true, false, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_stop_only_removes_vacation_tasks() {
+        let mut world = create_world!();
+        let now = Local::now();
+        let task = TaskRequest {
+            schedule: Schedule::default(),
+            cmd: LcnCommand::new(1632),
+            metadata: Metadata::default(),
+        };
+        let regular = super::super::request_processor::create_lcn_task(&mut world, task);
+        let request = request(vec![evening_window()], false);
+        let tasks = start(&mut world, &History::default(), request, &now);
+
+        assert_eq!(stop(&mut world).len(), tasks.len());
+        assert!(world.is_alive(regular));
+        assert!(tasks.iter().all(|t| !world.is_alive(*t)));
+    }
+}
@@ -1,14 +1,34 @@
+use super::super::backup::{ExportedTask, ImportMode, ImportReport, ImportRequest};
+use super::super::clock::Clock;
 use super::super::components::*;
+use super::super::config::{CommandConfig, ConflictAction, ConflictConfig};
+use super::super::events::{Event, EventBus};
+use super::super::history::History;
+use super::super::ics::CalendarTask;
 use super::super::requests::*;
-use lame_ecs::{Entity, World};
+use chrono::{Local, TimeZone};
+use lame_ecs::{component_iter, component_iter_mut, Entity, World};
 use rocket::tokio::sync::mpsc::UnboundedReceiver;
 use rocket::tokio::sync::oneshot::Sender;
 use rocket::tokio::time::timeout;
 
-pub async fn process(world: &mut World, rx: &mut UnboundedReceiver<Request>) -> Result<(), String> {
-    let seconds_to_next_task = get_seconds_to_next_execution(world);
+/// The timer does not advance while the machine is suspended, waking up
+/// regularly notices the time that passed meanwhile.
+const MAX_WAIT_SEC: u64 = 60;
+
+pub async fn process(
+    world: &mut World,
+    rx: &mut UnboundedReceiver<Request>,
+    history: &History,
+    catalog: &[CommandConfig],
+    conflicts: &ConflictConfig,
+    clock: &dyn Clock,
+    events: &EventBus,
+) -> Result<(), String> {
+    let seconds_to_next_task = get_seconds_to_next_execution(world, clock);
     let input = match seconds_to_next_task {
         Some(s) => {
+            let s = s.min(MAX_WAIT_SEC);
             println!("request_processor: blocking for {} seconds", s);
             let r = timeout(std::time::Duration::from_secs(s), rx.recv()).await;
             if r.is_err() {
@@ -25,25 +45,170 @@ pub async fn process(world: &mut World, rx: &mut UnboundedReceiver<Request>) ->
         Some(r) => r,
         None => return Err("Producer thread diconnected".to_owned()),
     };
+    handle_request(world, request, history, catalog, conflicts, clock, events);
+    Ok(())
+}
+
+fn handle_request(
+    world: &mut World,
+    request: Request,
+    history: &History,
+    catalog: &[CommandConfig],
+    conflicts: &ConflictConfig,
+    clock: &dyn Clock,
+    events: &EventBus,
+) {
     match request {
-        Request::NewTask(data) => {
-            let entity = create_lcn_task(world, data.1);
-            send_response(data.0, Response::NewTask(entity), "NewTask");
-        }
-        Request::RemoveTask(data) => {
-            let mut removed = false;
-            if world.is_alive(data.1) {
-                world.remove_entity(data.1);
-                removed = true;
+        Request::NewTask((tx, task)) => {
+            let result = validate_schedule(world, &task.schedule)
+                .map(|_| create_lcn_task(world, task))
+                .and_then(|entity| check_conflicts(world, catalog, conflicts, clock, entity));
+            if let Ok((entity, _)) = &result {
+                emit_created(world, events, entity);
             }
-            send_response(data.0, Response::RemoveTask(removed), "RemoveTask");
+            send_response(tx, Response::NewTask(result), "NewTask");
         }
-        Request::GetStatus(tx) => {
-            let status = super::status_reporter::get_status(world);
+        Request::RemoveTask((tx, entity)) => {
+            let result = match is_task(world, entity) {
+                false => Err(format!("no task with id {} exists", entity.id())),
+                true if is_file_managed(world, entity) => Err(read_only(entity)),
+                true => {
+                    world.remove_entity(entity);
+                    emit_removed(events, vec![entity]);
+                    Ok(())
+                }
+            };
+            send_response(tx, Response::RemoveTask(result), "RemoveTask");
+        }
+        Request::GetStatus((tx, order)) => {
+            let status = super::status_reporter::get_status(world, catalog, &clock.now(), order);
             send_response(tx, Response::GetStatus(status), "GetStatus");
         }
+        Request::StartVacation(data) => {
+            let now = clock.now();
+            emit_removed(events, super::presence_simulator::stop(world));
+            let tasks = super::presence_simulator::start(world, history, data.1, &now);
+            for task in &tasks {
+                emit_created(world, events, task);
+            }
+            send_response(data.0, Response::StartVacation(tasks), "StartVacation");
+        }
+        Request::StopVacation(tx) => {
+            let removed = super::presence_simulator::stop(world);
+            let count = removed.len();
+            emit_removed(events, removed);
+            send_response(tx, Response::StopVacation(count), "StopVacation");
+        }
+        Request::Simulate(data) => {
+            let mut rng = rand::thread_rng();
+            let runs = super::simulation::simulate(world, clock.now(), data.1, &mut rng);
+            for run in &runs {
+                println!(
+                    "simulation: {} task {} command {}",
+                    run.time, run.task_id, run.cmd.id
+                );
+            }
+            send_response(data.0, Response::Simulate(runs), "Simulate");
+        }
+        Request::GetConflicts((tx, days)) => {
+            let mut rng = rand::thread_rng();
+            let found = super::conflict_detector::find_conflicts(
+                world,
+                catalog,
+                clock.now(),
+                days.unwrap_or(conflicts.days),
+                conflicts.window_sec,
+                &mut rng,
+            );
+            send_response(tx, Response::GetConflicts(found), "GetConflicts");
+        }
+        Request::ExecuteCommand((tx, cmd)) => {
+            let entity = create_immediate_command(world, cmd);
+            emit_created(world, events, &entity);
+            send_response(tx, Response::ExecuteCommand(entity), "ExecuteCommand");
+        }
+        Request::SetTaskEnabled((tx, (entity, enabled))) => {
+            let changed = set_task_enabled(world, entity, enabled);
+            if changed {
+                events.emit(Event::TaskEnabled {
+                    task_id: entity.id(),
+                    enabled,
+                });
+            }
+            send_response(tx, Response::SetTaskEnabled(changed), "SetTaskEnabled");
+        }
+        Request::ExecuteScene((tx, cmds)) => {
+            let entities = cmds
+                .into_iter()
+                .map(|cmd| {
+                    let entity = create_immediate_command(world, cmd);
+                    emit_created(world, events, &entity);
+                    entity
+                })
+                .collect();
+            send_response(tx, Response::ExecuteScene(entities), "ExecuteScene");
+        }
+        Request::SetAlarm((tx, time)) => {
+            let rescheduled = set_alarm(world, time, clock);
+            send_response(tx, Response::SetAlarm(rescheduled), "SetAlarm");
+        }
+        Request::ExportSchedule(tx) => {
+            let tasks = calendar_tasks(world);
+            send_response(tx, Response::ExportSchedule(tasks), "ExportSchedule");
+        }
+        Request::TagAction((tx, (tag, action))) => {
+            let tasks = tagged_tasks(world, &tag);
+            let affected = match action {
+                TagAction::Pause | TagAction::Resume => {
+                    let enabled = action == TagAction::Resume;
+                    let changed: Vec<Entity> = tasks
+                        .into_iter()
+                        .filter(|entity| set_task_enabled(world, *entity, enabled))
+                        .collect();
+                    for entity in &changed {
+                        events.emit(Event::TaskEnabled {
+                            task_id: entity.id(),
+                            enabled,
+                        });
+                    }
+                    changed
+                }
+                TagAction::Remove => {
+                    for entity in &tasks {
+                        world.remove_entity(*entity);
+                    }
+                    emit_removed(events, tasks.clone());
+                    tasks
+                }
+            };
+            println!("{:?} {} tasks tagged {}", action, affected.len(), tag);
+            send_response(tx, Response::TagAction(affected), "TagAction");
+        }
+        Request::ExportTasks(tx) => {
+            let tasks = exported_tasks(world);
+            send_response(tx, Response::ExportTasks(tasks), "ExportTasks");
+        }
+        Request::ImportTasks((tx, request)) => {
+            let report = import_tasks(world, events, request);
+            send_response(tx, Response::ImportTasks(report), "ImportTasks");
+        }
+        Request::SyncFileTasks((tx, tasks)) => {
+            let result = tasks
+                .iter()
+                .try_for_each(|t| {
+                    validate_schedule(world, &t.task.schedule)
+                        .map_err(|e| format!("task {}: {}", t.key, e))
+                })
+                .map(|_| super::file_tasks::sync(world, tasks));
+            if let Ok(report) = &result {
+                for entity in &report.added {
+                    emit_created(world, events, entity);
+                }
+                emit_removed(events, report.removed.clone());
+            }
+            send_response(tx, Response::SyncFileTasks(result), "SyncFileTasks");
+        }
     }
-    Ok(())
 }
 
 fn send_response(tx: Sender<Response>, response: Response, tag: &str) {
@@ -51,19 +216,302 @@ fn send_response(tx: Sender<Response>, response: Response, tag: &str) {
     result.unwrap_or_else(|_| panic!("process_request({}): failed to send response", tag));
 }
 
-fn create_lcn_task(world: &mut World, task: TaskRequest) -> Entity {
+fn validate_schedule(world: &World, schedule: &Schedule) -> Result<(), String> {
+    schedule
+        .timezone()
+        .map_err(|e| format!("invalid time zone: {}", e))?;
+    let repeats = schedule.weekdays.contains(&true);
+    if schedule.alarm.is_some() && !repeats {
+        return Err("alarm relative tasks need at least one weekday".to_owned());
+    }
+    if schedule.date.is_some() && repeats {
+        return Err("tasks on a date cannot repeat on weekdays".to_owned());
+    }
+    if let (Some(from), Some(until)) = (schedule.active_from, schedule.active_until) {
+        if from > until {
+            return Err("validity ends before it starts".to_owned());
+        }
+    }
+    if let Some(calendar) = &schedule.calendar {
+        let known = component_iter!(world, Calendar).any(|(c, _)| c.name == calendar.name);
+        if !known {
+            return Err(format!("unknown calendar {}", calendar.name));
+        }
+    }
+    Ok(())
+}
+
+pub fn create_lcn_task(world: &mut World, task: TaskRequest) -> Entity {
     println!("new lcn task {}", serde_json::to_string(&task).unwrap());
     let entity = world.new_entity();
     world.add_component(entity, task.schedule);
     world.add_component(entity, ActivationState::ToBeScheduled);
     world.add_component(entity, task.cmd);
+    if task.metadata != Metadata::default() {
+        world.add_component(entity, task.metadata);
+    }
+    entity
+}
+
+/// Immediate commands have no schedule, the executor removes them once
+/// they have been executed.
+fn create_immediate_command(world: &mut World, cmd: LcnCommand) -> Entity {
+    println!(
+        "new immediate lcn command {}",
+        serde_json::to_string(&cmd).unwrap()
+    );
+    let entity = world.new_entity();
+    world.add_component(entity, ActivationState::ReadyToRun);
+    world.add_component(entity, cmd);
     entity
 }
 
-fn get_seconds_to_next_execution(world: &World) -> Option<u64> {
+/// Paused tasks keep their schedule but are skipped by the scheduler until
+/// they are enabled again. File managed tasks are paused in the file.
+fn set_task_enabled(world: &mut World, entity: Entity, enabled: bool) -> bool {
+    if !world.is_alive(entity)
+        || world.get_component::<Schedule>(entity).is_none()
+        || is_file_managed(world, entity)
+    {
+        return false;
+    }
+    let state = match world.get_component::<ActivationState>(entity) {
+        Some(state) => state,
+        None => return false,
+    };
+    let paused = *state == ActivationState::Paused;
+    if enabled != paused {
+        return false;
+    }
+    *state = match enabled {
+        true => ActivationState::ToBeScheduled,
+        false => ActivationState::Paused,
+    };
+    println!(
+        "task {} {}",
+        entity.id(),
+        if enabled { "enabled" } else { "paused" }
+    );
+    true
+}
+
+/// A pushed alarm replaces the one of the same day, alarms older than a
+/// day are dropped. Returns the number of tasks that were rescheduled.
+fn set_alarm(world: &mut World, time: i64, clock: &dyn Clock) -> usize {
+    let date = Local.timestamp(time, 0).date();
+    let oldest = clock.now().timestamp() - 24 * 3600;
+    let replaced: Vec<Entity> = component_iter!(world, Alarm)
+        .filter(|(alarm, _)| alarm.time < oldest || Local.timestamp(alarm.time, 0).date() == date)
+        .map(|(_, entity)| *entity)
+        .collect();
+    for entity in replaced {
+        world.remove_entity(entity);
+    }
+    let entity = world.new_entity();
+    world.add_component(entity, Alarm { time });
+    println!("alarm set to {}", Local.timestamp(time, 0).to_rfc2822());
+
+    let mut rescheduled = 0;
+    for (state, schedule, _) in component_iter_mut!(world, ActivationState, Schedule) {
+        if schedule.alarm.is_some() && matches!(state, ActivationState::Scheduled(_)) {
+            *state = ActivationState::ToBeScheduled;
+            rescheduled += 1;
+        }
+    }
+    rescheduled
+}
+
+fn calendar_tasks(world: &World) -> Vec<CalendarTask> {
+    let range = component_iter!(world, ActivationState, Schedule, LcnCommand);
+    range
+        .map(|(state, schedule, cmd, entity)| CalendarTask {
+            id: entity.id(),
+            schedule: schedule.clone(),
+            cmd: cmd.clone(),
+            next_run: match state {
+                ActivationState::Scheduled(t) => Some(*t),
+                _ => None,
+            },
+        })
+        .collect()
+}
+
+/// Looks for conflicts of a new task with the existing ones and removes it
+/// again if they are to be rejected.
+fn check_conflicts(
+    world: &mut World,
+    catalog: &[CommandConfig],
+    config: &ConflictConfig,
+    clock: &dyn Clock,
+    entity: Entity,
+) -> Result<(Entity, Vec<String>), String> {
+    let mut rng = rand::thread_rng();
+    let found = super::conflict_detector::find_task_conflicts(
+        world,
+        catalog,
+        entity,
+        clock.now(),
+        config.days,
+        config.window_sec,
+        &mut rng,
+    );
+    // the same pair usually collides on every day, the first time tells enough
+    let mut pairs = std::collections::HashSet::new();
+    let involved: Vec<String> = found
+        .iter()
+        .filter(|c| pairs.insert((c.first.task_id, c.second.task_id)))
+        .map(|c| super::conflict_detector::describe(catalog, c))
+        .collect();
+    if !involved.is_empty() && config.action == ConflictAction::Reject {
+        world.remove_entity(entity);
+        return Err(involved.join("; "));
+    }
+    for warning in &involved {
+        println!("request_processor: warning: {}", warning);
+    }
+    Ok((entity, involved))
+}
+
+/// Alarms and calendars are entities too, but no tasks.
+fn is_task(world: &World, entity: Entity) -> bool {
+    world.is_alive(entity)
+        && world.get_component::<LcnCommand>(entity).is_some()
+        && world.get_component::<Schedule>(entity).is_some()
+}
+
+fn is_file_managed(world: &World, entity: Entity) -> bool {
+    world.get_component::<FileManaged>(entity).is_some()
+}
+
+fn read_only(entity: Entity) -> String {
+    format!("task {} is managed by the tasks file", entity.id())
+}
+
+/// Vacation tasks are left out, vacation mode creates them anew, and file
+/// managed tasks belong to the tasks file.
+fn managed_tasks(world: &World) -> Vec<Entity> {
+    component_iter!(world, Schedule, LcnCommand)
+        .map(|(_, _, entity)| *entity)
+        .filter(|entity| world.get_component::<Vacation>(*entity).is_none())
+        .filter(|entity| !is_file_managed(world, *entity))
+        .collect()
+}
+
+/// Expired tasks will not run again and are not exported.
+fn exported_tasks(world: &World) -> Vec<ExportedTask> {
+    managed_tasks(world)
+        .into_iter()
+        .filter(|entity| {
+            world.get_component::<ActivationState>(*entity).as_deref()
+                != Some(&ActivationState::Expired)
+        })
+        .filter_map(|entity| exported_task(world, entity))
+        .collect()
+}
+
+pub fn exported_task(world: &World, entity: Entity) -> Option<ExportedTask> {
+    let state = world.get_component::<ActivationState>(entity)?;
+    Some(ExportedTask {
+        schedule: world.get_component::<Schedule>(entity)?.clone(),
+        cmd: world.get_component::<LcnCommand>(entity)?.clone(),
+        metadata: world
+            .get_component::<Metadata>(entity)
+            .cloned()
+            .unwrap_or_default(),
+        enabled: *state != ActivationState::Paused,
+    })
+}
+
+/// Nothing is changed unless all imported tasks are valid.
+fn import_tasks(
+    world: &mut World,
+    events: &EventBus,
+    request: ImportRequest,
+) -> Result<ImportReport, String> {
+    for (i, task) in request.tasks.iter().enumerate() {
+        validate_schedule(world, &task.schedule).map_err(|e| format!("task {}: {}", i + 1, e))?;
+    }
+    let mut report = ImportReport {
+        dry_run: request.dry_run,
+        ..Default::default()
+    };
+    let kept = match request.mode {
+        ImportMode::Merge => exported_tasks(world),
+        ImportMode::Replace => {
+            report.removed = managed_tasks(world).iter().map(Entity::id).collect();
+            Vec::new()
+        }
+    };
+    for task in request.tasks {
+        let duplicate = kept
+            .iter()
+            .chain(&report.added)
+            .any(|t| t.same_task(&task.schedule, &task.cmd));
+        match duplicate {
+            true => report.skipped += 1,
+            false => report.added.push(task),
+        }
+    }
+    if request.dry_run {
+        return Ok(report);
+    }
+    let removed: Vec<Entity> = report.removed.iter().map(|id| Entity::new(*id)).collect();
+    for entity in &removed {
+        world.remove_entity(*entity);
+    }
+    emit_removed(events, removed);
+    for task in &report.added {
+        let request = TaskRequest {
+            schedule: task.schedule.clone(),
+            cmd: task.cmd.clone(),
+            metadata: task.metadata.clone(),
+        };
+        let entity = create_lcn_task(world, request);
+        if !task.enabled {
+            set_task_enabled(world, entity, false);
+        }
+        emit_created(world, events, &entity);
+    }
+    println!(
+        "import: {} tasks added, {} removed, {} skipped",
+        report.added.len(),
+        report.removed.len(),
+        report.skipped
+    );
+    Ok(report)
+}
+
+/// File managed tasks are left alone.
+fn tagged_tasks(world: &World, tag: &str) -> Vec<Entity> {
+    component_iter!(world, Metadata, Schedule)
+        .filter(|(metadata, _, _)| metadata.tags.contains(tag))
+        .map(|(_, _, entity)| *entity)
+        .filter(|entity| !is_file_managed(world, *entity))
+        .collect()
+}
+
+fn emit_created(world: &World, events: &EventBus, entity: &Entity) {
+    if let Some(cmd) = world.get_component::<LcnCommand>(*entity) {
+        events.emit(Event::TaskCreated {
+            task_id: entity.id(),
+            cmd: cmd.clone(),
+            immediate: world.get_component::<Schedule>(*entity).is_none(),
+        });
+    }
+}
+
+fn emit_removed(events: &EventBus, entities: Vec<Entity>) {
+    for entity in entities {
+        events.emit(Event::TaskRemoved {
+            task_id: entity.id(),
+        });
+    }
+}
+
+fn get_seconds_to_next_execution(world: &World, clock: &dyn Clock) -> Option<u64> {
     let mut time: Option<i64> = None;
-    let now = chrono::Local::now().timestamp();
-    for (state, _) in lame_ecs::component_iter!(world, ActivationState) {
+    let now = clock.now().timestamp();
+    for (state, _) in component_iter!(world, ActivationState) {
         match state {
             ActivationState::Scheduled(t) if t < &time.unwrap_or(i64::MAX) => time = Some(*t),
             ActivationState::ReadyToRun => return Some(0),
@@ -71,8 +519,74 @@ fn get_seconds_to_next_execution(world: &World) -> Option<u64> {
         }
     }
     let seconds = time? - now;
-    if seconds < 0 {
-        panic!("task should be executed in the past but is not ready to run");
+    // jittered activations may be clamped to the current second and become
+    // due before the scheduler gets the chance to mark them ready to run
+    Some(std::cmp::max(seconds, 0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::clock::ManualClock;
+    use super::*;
+    use lame_ecs::create_world;
+    use rocket::tokio::sync::oneshot;
+
+    fn request(
+        world: &mut World,
+        events: &EventBus,
+        make: impl FnOnce(Sender<Response>) -> Request,
+    ) -> Response {
+        let clock = ManualClock::new(Local.ymd(2021, 6, 16).and_hms(7, 0, 0));
+        let (tx, mut rx) = oneshot::channel();
+        let conflicts = ConflictConfig::default();
+        let history = History::default();
+        handle_request(world, make(tx), &history, &[], &conflicts, &clock, events);
+        rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn test_scene_commands_are_announced() {
+        let mut world = create_world!();
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let cmds = vec![LcnCommand::new(1633), LcnCommand::new(1623)];
+        let entities = match request(&mut world, &events, |tx| Request::ExecuteScene((tx, cmds))) {
+            Response::ExecuteScene(entities) => entities,
+            r => panic!("unexpected response {:?}", r),
+        };
+        for entity in entities {
+            match rx.try_recv() {
+                Ok(Event::TaskCreated {
+                    task_id,
+                    immediate: true,
+                    ..
+                }) => assert_eq!(task_id, entity.id()),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_only_tasks_are_removed() {
+        let mut world = create_world!();
+        let events = EventBus::new();
+        let alarm = world.new_entity();
+        world.add_component(alarm, Alarm { time: 0 });
+        let calendar = world.new_entity();
+        world.add_component(calendar, Calendar::default());
+        let task = world.new_entity();
+        world.add_component(task, Schedule::default());
+        world.add_component(task, ActivationState::ToBeScheduled);
+        world.add_component(task, LcnCommand::new(1623));
+
+        for entity in [alarm, calendar] {
+            let response = request(&mut world, &events, |tx| Request::RemoveTask((tx, entity)));
+            let expected = format!("no task with id {} exists", entity.id());
+            assert!(matches!(response, Response::RemoveTask(Err(e)) if e == expected));
+            assert!(world.is_alive(entity));
+        }
+        let response = request(&mut world, &events, |tx| Request::RemoveTask((tx, task)));
+        assert!(matches!(response, Response::RemoveTask(Ok(()))));
+        assert!(!world.is_alive(task));
     }
-    Some(seconds as u64)
 }
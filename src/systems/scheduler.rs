@@ -1,71 +1,432 @@
+use super::super::clock::Clock;
+use super::super::components::schedule::{AlarmFallback, AlarmOffset, CalendarMode, Misfire};
 use super::super::components::*;
-use chrono::{Datelike, Local, TimeZone};
-use lame_ecs::{component_iter_mut, Entity, World};
+use super::super::events::{Event, EventBus};
+use chrono::{DateTime, Datelike, LocalResult, NaiveDate, NaiveTime, TimeZone, Timelike};
+use lame_ecs::{component_iter, component_iter_mut, Entity, World};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt::Display;
 
-pub fn process(world: &mut World) {
-    let now = chrono::Local::now();
-    process_internal(world, &now);
+// far enough for calendars listing a single date per year
+const MAX_LOOKAHEAD_DAYS: i64 = 366;
+// later runs are misfires, earlier ones just the delay of the event loop
+const MISFIRE_AFTER_SEC: i64 = 60;
+// bounds the catch up after a long downtime
+const MAX_MISSED_RUNS: u32 = 100;
+
+pub fn process(world: &mut World, clock: &dyn Clock, rng: &mut impl Rng, events: &EventBus) {
+    let calendars = calendars(world);
+    process_with_calendars(world, clock, rng, events, &calendars);
 }
 
-fn process_internal(world: &mut World, now: &chrono::DateTime<chrono::Local>) {
+/// Schedules against `calendars` instead of the calendars of the world, a
+/// simulation takes them once for all of its steps.
+pub fn process_with_calendars(
+    world: &mut World,
+    clock: &dyn Clock,
+    rng: &mut impl Rng,
+    events: &EventBus,
+    calendars: &[Calendar],
+) {
+    let now = clock.now();
+    for event in schedule_tasks(world, &now, rng, calendars) {
+        events.emit(event);
+    }
+}
+
+pub fn calendars(world: &World) -> Vec<Calendar> {
+    component_iter!(world, Calendar)
+        .map(|(calendar, _)| calendar.clone())
+        .collect()
+}
+
+fn schedule_tasks<Tz: TimeZone>(
+    world: &mut World,
+    now: &DateTime<Tz>,
+    rng: &mut impl Rng,
+    calendars: &[Calendar],
+) -> Vec<Event>
+where
+    Tz::Offset: Display,
+{
+    let mut events = Vec::new();
     let mut to_be_removed: Vec<Entity> = Vec::new();
+    let mut catch_up: Vec<(Entity, u32)> = Vec::new();
+    let alarms: Vec<i64> = component_iter!(world, Alarm)
+        .map(|(alarm, _)| alarm.time)
+        .collect();
+    let occurrences: HashMap<i64, Occurrence> = component_iter!(world, Occurrence)
+        .map(|(occurrence, entity)| (entity.id(), *occurrence))
+        .collect();
+    let mut occurred: Vec<(Entity, Occurrence)> = Vec::new();
     let range = component_iter_mut!(world, ActivationState, Schedule);
     for (state, schedule, entity) in range {
-        if *state == ActivationState::ReadyToRun {
+        if matches!(
+            state,
+            ActivationState::ReadyToRun | ActivationState::Paused | ActivationState::Expired
+        ) {
             continue;
         }
+        let calendar = schedule
+            .calendar
+            .as_ref()
+            .and_then(|r| calendars.iter().find(|c| c.name == r.name));
+        let mut occurrence = occurrences.get(&entity.id()).copied().unwrap_or_default();
+        let mut context = Context {
+            alarms: &alarms,
+            calendar,
+            last: occurrence.last,
+        };
         if let ActivationState::Scheduled(activation_time) = *state {
-            if activation_time <= now.timestamp() {
+            if activation_time > now.timestamp() {
+                continue;
+            }
+            let late = now.timestamp() - activation_time;
+            let runs = match late > MISFIRE_AFTER_SEC {
+                false => 1,
+                true => {
+                    let missed = match schedule.timezone() {
+                        Ok(Some(tz)) => missed_runs(
+                            schedule,
+                            activation_time,
+                            &now.with_timezone(&tz),
+                            &context,
+                        ),
+                        _ => missed_runs(schedule, activation_time, now, &context),
+                    };
+                    let runs = misfire_runs(schedule.misfire, late, missed);
+                    println!(
+                        "Entity {} missed {} runs since {}, making up for {}",
+                        entity.id(),
+                        missed,
+                        activation_time,
+                        runs
+                    );
+                    events.push(Event::TaskMisfired {
+                        task_id: entity.id(),
+                        time: activation_time,
+                        missed,
+                        runs,
+                    });
+                    runs
+                }
+            };
+            occurrence.last = occurrence.next.or(Some(activation_time));
+            context.last = occurrence.last;
+            occurred.push((*entity, occurrence));
+            if runs > 0 {
                 *state = ActivationState::ReadyToRun;
                 println!("Entity {} ready to run", entity.id());
+                events.push(Event::TaskReadyToRun {
+                    task_id: entity.id(),
+                });
+                if runs > 1 {
+                    catch_up.push((*entity, runs - 1));
+                }
+                continue;
             }
-            continue;
+            // skipped runs leave the task to be scheduled anew
         }
-        let activation_date = Local.ymd(now.year(), now.month(), now.day()).and_hms(
-            schedule.hour as u32,
-            schedule.min as u32,
-            schedule.sec as u32,
-        );
-        let today = now.weekday().num_days_from_monday() as usize;
-        if activation_date >= *now && (!has_repeat(&schedule.weekdays) || schedule.weekdays[today])
-        {
-            *state = ActivationState::Scheduled(activation_date.timestamp());
-            println!(
-                "Entity {} scheduled0 {}",
-                entity.id(),
-                activation_date.to_rfc2822()
-            );
+        // tasks whose calendar failed to load wait for it, the status shows them
+        if schedule.calendar.is_some() && calendar.is_none() {
+            *state = ActivationState::ToBeScheduled;
             continue;
         }
-        let days = days_to_next_run(now.weekday().num_days_from_monday(), &schedule.weekdays);
-        if days.is_none() {
-            to_be_removed.push(*entity);
-            continue;
+        let activation_time = match schedule.timezone() {
+            Ok(Some(tz)) => schedule_task(entity, schedule, &now.with_timezone(&tz), &context, rng),
+            Ok(None) => schedule_task(entity, schedule, now, &context, rng),
+            Err(e) => {
+                println!("Entity {} has an invalid time zone: {}", entity.id(), e);
+                None
+            }
+        };
+        match activation_time {
+            Some((base, t)) => {
+                occurrence.next = Some(base);
+                occurred.push((*entity, occurrence));
+                *state = ActivationState::Scheduled(t);
+                events.push(Event::TaskScheduled {
+                    task_id: entity.id(),
+                    time: t,
+                });
+            }
+            // expired tasks are kept to show up in the status
+            None if is_expired(schedule, now) => {
+                *state = ActivationState::Expired;
+                println!("Entity {} expired", entity.id());
+                events.push(Event::TaskExpired {
+                    task_id: entity.id(),
+                });
+            }
+            // alarm relative tasks wait for the next alarm to be pushed
+            None if schedule.alarm.is_some() => {}
+            None => to_be_removed.push(*entity),
+        }
+    }
+    for (entity, occurrence) in occurred {
+        match world.get_component::<Occurrence>(entity) {
+            Some(previous) => *previous = occurrence,
+            None => world.add_component(entity, occurrence),
         }
-        let activation_date = activation_date + chrono::Duration::days(days.unwrap());
-        *state = ActivationState::Scheduled(activation_date.timestamp());
-        println!(
-            "Entity {} scheduled1: {}",
-            entity.id(),
-            activation_date.to_rfc2822()
-        );
     }
     for entity in to_be_removed {
         world.remove_entity(entity);
         println!("Entity {} removed", entity.id());
+        events.push(Event::TaskRemoved {
+            task_id: entity.id(),
+        });
     }
+    // further missed runs are made up for by immediate commands
+    for (entity, runs) in catch_up {
+        let cmd = match world.get_component::<LcnCommand>(entity) {
+            Some(cmd) => cmd.clone(),
+            None => continue,
+        };
+        for _ in 0..runs {
+            let extra = world.new_entity();
+            world.add_component(extra, ActivationState::ReadyToRun);
+            world.add_component(extra, cmd.clone());
+            world.add_component(extra, CatchUp);
+            println!(
+                "Entity {} makes up for a run of {}",
+                extra.id(),
+                entity.id()
+            );
+        }
+    }
+    // immediate commands have no schedule, but may be deferred by the executor
+    let deferred: Vec<Entity> = component_iter!(world, ActivationState, LcnCommand)
+        .filter(
+            |(state, _, _)| matches!(state, ActivationState::Scheduled(t) if *t <= now.timestamp()),
+        )
+        .map(|(_, _, entity)| *entity)
+        .filter(|entity| world.get_component::<Schedule>(*entity).is_none())
+        .collect();
+    for entity in deferred {
+        *world.get_component::<ActivationState>(entity).unwrap() = ActivationState::ReadyToRun;
+        println!("Entity {} ready to run", entity.id());
+    }
+    events
+}
+
+/// What the scheduling of a task depends on besides its schedule.
+struct Context<'a> {
+    alarms: &'a [i64],
+    calendar: Option<&'a Calendar>,
+    last: Option<i64>,
 }
 
-fn days_to_next_run(mut weekday: u32, weekdays: &[bool]) -> Option<i64> {
-    let mut days = 1;
-    while days < weekdays.len() {
-        weekday = (weekday + 1) % weekdays.len() as u32;
-        if weekdays[weekday as usize] {
-            return Some(days as i64);
+/// Counts the runs from the missed one up to now, jitter aside.
+fn missed_runs<Tz: TimeZone>(
+    schedule: &Schedule,
+    missed: i64,
+    now: &DateTime<Tz>,
+    context: &Context,
+) -> u32 {
+    let mut count = 1;
+    // a jittered run may come before its occurrence, which was missed as well
+    let mut cursor = now
+        .timezone()
+        .timestamp(missed + schedule.jitter_min as i64 * 60, 0);
+    while count < MAX_MISSED_RUNS {
+        match activation(schedule, &cursor, context) {
+            Some(t) if t <= *now => {
+                count += 1;
+                cursor = t;
+            }
+            _ => break,
         }
-        days += 1;
     }
-    None
+    count
+}
+
+fn misfire_runs(misfire: Misfire, late: i64, missed: u32) -> u32 {
+    match misfire {
+        Misfire::RunOnce { grace_min } if late <= grace_min as i64 * 60 => 1,
+        Misfire::RunOnce { .. } | Misfire::Skip => 0,
+        Misfire::RunAll => missed,
+    }
+}
+
+/// Returns the next occurrence and the jittered time it runs at.
+fn schedule_task<Tz: TimeZone>(
+    entity: &Entity,
+    schedule: &Schedule,
+    now: &DateTime<Tz>,
+    context: &Context,
+    rng: &mut impl Rng,
+) -> Option<(i64, i64)>
+where
+    Tz::Offset: Display,
+{
+    // a run jittered to before its occurrence must not find it again
+    let after = match context.last {
+        Some(last) if last > now.timestamp() => now.timezone().timestamp(last, 0),
+        _ => now.clone(),
+    };
+    let base = activation(schedule, &after, context)?;
+    let activation_date = apply_jitter(base.clone(), schedule.jitter_min, now, rng);
+    println!(
+        "Entity {} scheduled: {}",
+        entity.id(),
+        activation_date.to_rfc2822()
+    );
+    Some((base.timestamp(), activation_date.timestamp()))
+}
+
+fn activation<Tz: TimeZone>(
+    schedule: &Schedule,
+    now: &DateTime<Tz>,
+    context: &Context,
+) -> Option<DateTime<Tz>> {
+    match (&schedule.date, &schedule.alarm) {
+        (None, Some(alarm)) => next_alarm_activation(schedule, alarm, now, context),
+        _ => next_activation(schedule, now, context.calendar),
+    }
+}
+
+fn next_activation<Tz: TimeZone>(
+    schedule: &Schedule,
+    now: &DateTime<Tz>,
+    calendar: Option<&Calendar>,
+) -> Option<DateTime<Tz>> {
+    let time = NaiveTime::from_hms_opt(
+        schedule.hour as u32,
+        schedule.min as u32,
+        schedule.sec as u32,
+    )?;
+    if let Some(date) = schedule.date {
+        let activation_date = resolve_local_time(&now.timezone(), date, time);
+        return Some(activation_date).filter(|t| t > now);
+    }
+    let today = now.naive_local().date();
+    let first = std::cmp::max(today, schedule.active_from.unwrap_or(today));
+    let activation_date = resolve_local_time(&now.timezone(), first, time);
+    // strictly after now: a task that just ran within this second must not
+    // be scheduled again for today
+    if activation_date > *now && runs_on(schedule, calendar, first) {
+        return Some(activation_date);
+    }
+    if !repeats(schedule) {
+        return None;
+    }
+    let days = days_to_next_run(first, |date| runs_on(schedule, calendar, date))?;
+    let date = first + chrono::Duration::days(days);
+    Some(resolve_local_time(&now.timezone(), date, time))
+}
+
+/// The alarm of a day is the one falling on that date in the task's time
+/// zone, days without one use the fixed time or are skipped.
+fn next_alarm_activation<Tz: TimeZone>(
+    schedule: &Schedule,
+    alarm: &AlarmOffset,
+    now: &DateTime<Tz>,
+    context: &Context,
+) -> Option<DateTime<Tz>> {
+    let time = NaiveTime::from_hms_opt(
+        schedule.hour as u32,
+        schedule.min as u32,
+        schedule.sec as u32,
+    )?;
+    let tz = now.timezone();
+    let today = now.naive_local().date();
+    let offset = chrono::Duration::minutes(alarm.offset_min as i64);
+    // a later alarm for a day the task already ran on must not run it again
+    let last_date = context
+        .last
+        .map(|t| tz.timestamp(t, 0).naive_local().date());
+    (0..=7)
+        .filter_map(|days| {
+            let date = today + chrono::Duration::days(days);
+            if !runs_on(schedule, context.calendar, date) {
+                return None;
+            }
+            let pushed = context
+                .alarms
+                .iter()
+                .map(|t| tz.timestamp(*t, 0))
+                .find(|t| t.naive_local().date() == date);
+            match (pushed, alarm.fallback) {
+                (Some(t), _) => Some(t + offset),
+                (None, AlarmFallback::Default) => Some(resolve_local_time(&tz, date, time)),
+                (None, AlarmFallback::Skip) => None,
+            }
+        })
+        .find(|t| t > now && last_date.is_none_or(|last| t.naive_local().date() > last))
+}
+
+/// Wall clock times skipped by a forward DST transition are shifted to the
+/// first valid time after the gap, repeated ones resolve to their first
+/// occurrence.
+fn resolve_local_time<Tz: TimeZone>(tz: &Tz, date: NaiveDate, time: NaiveTime) -> DateTime<Tz> {
+    let mut local = date.and_time(time);
+    loop {
+        match tz.from_local_datetime(&local) {
+            LocalResult::Single(t) => return t,
+            LocalResult::Ambiguous(first, _) => return first,
+            LocalResult::None => {
+                local = local.with_second(0).unwrap().with_nanosecond(0).unwrap()
+                    + chrono::Duration::minutes(1);
+            }
+        }
+    }
+}
+
+fn apply_jitter<Tz: TimeZone>(
+    activation_date: DateTime<Tz>,
+    jitter_min: u16,
+    now: &DateTime<Tz>,
+    rng: &mut impl Rng,
+) -> DateTime<Tz> {
+    if jitter_min == 0 {
+        return activation_date;
+    }
+    let jitter = jitter_min as i64 * 60;
+    let offset = chrono::Duration::seconds(rng.gen_range(-jitter..=jitter));
+    std::cmp::max(activation_date + offset, now.clone())
+}
+
+fn days_to_next_run(today: NaiveDate, runs_on: impl Fn(NaiveDate) -> bool) -> Option<i64> {
+    (1..=MAX_LOOKAHEAD_DAYS).find(|days| runs_on(today + chrono::Duration::days(*days)))
+}
+
+/// Without a next activation a task has expired once the end of its
+/// validity is reached, alarm relative tasks only after that day since the
+/// alarm of the last day may still be pushed.
+fn is_expired<Tz: TimeZone>(schedule: &Schedule, now: &DateTime<Tz>) -> bool {
+    let today = now.naive_local().date();
+    match schedule.active_until {
+        Some(until) if schedule.alarm.is_some() => today > until,
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Tasks running only on the dates of a calendar repeat even without
+/// weekdays.
+fn repeats(schedule: &Schedule) -> bool {
+    let only = matches!(&schedule.calendar, Some(r) if r.mode == CalendarMode::Only);
+    has_repeat(&schedule.weekdays) || only
+}
+
+/// Only called with the calendar of the schedule, if it has one.
+fn runs_on(schedule: &Schedule, calendar: Option<&Calendar>, date: NaiveDate) -> bool {
+    if schedule.active_from.is_some_and(|from| date < from)
+        || schedule.active_until.is_some_and(|until| date > until)
+    {
+        return false;
+    }
+    let weekday = date.weekday().num_days_from_monday() as usize;
+    if has_repeat(&schedule.weekdays) && !schedule.weekdays[weekday] {
+        return false;
+    }
+    let listed = calendar.is_some_and(|c| c.dates.contains(&date));
+    match &schedule.calendar {
+        Some(r) if r.mode == CalendarMode::Only => listed,
+        Some(_) => !listed,
+        None => true,
+    }
 }
 
 fn has_repeat(weekdays: &[bool]) -> bool {
@@ -79,19 +440,40 @@ fn has_repeat(weekdays: &[bool]) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use super::super::super::clock::ManualClock;
+    use super::super::super::components::schedule::CalendarRef;
     use super::*;
-    use chrono::{Duration, Timelike};
+    use chrono::{Duration, Local, Utc};
+    use chrono_tz::America::New_York;
+    use chrono_tz::Europe::Berlin;
     use lame_ecs::create_world;
+    use rand::{rngs::StdRng, SeedableRng};
 
-    fn to_schedule(date_time: chrono::DateTime<chrono::Local>) -> Schedule {
+    fn process_internal<Tz: TimeZone>(
+        world: &mut World,
+        now: &DateTime<Tz>,
+        rng: &mut impl Rng,
+    ) -> Vec<Event>
+    where
+        Tz::Offset: Display,
+    {
+        let calendars = calendars(world);
+        schedule_tasks(world, now, rng, &calendars)
+    }
+
+    fn to_schedule<Tz: TimeZone>(date_time: DateTime<Tz>) -> Schedule {
         Schedule {
             hour: date_time.hour() as i8,
             min: date_time.minute() as i8,
             sec: date_time.second() as i8,
-            weekdays: [false; 7],
+            ..Default::default()
         }
     }
 
+    fn test_now() -> chrono::DateTime<Local> {
+        Local.ymd(2021, 6, 16).and_hms(12, 0, 0)
+    }
+
     fn new_action(world: &mut World, schedule: Schedule) -> Entity {
         let entity = world.new_entity();
         world.add_component(entity, schedule);
@@ -102,58 +484,79 @@ mod tests {
     #[test]
     fn test_one_time_action() {
         let mut world = create_world!();
-        let mut now = chrono::Local::now();
-        if now.hour() >= 23 {
-            now = now - Duration::hours(1);
-        }
-        let action = new_action(&mut world, to_schedule(now + Duration::hours(1)));
+        let mut rng = StdRng::seed_from_u64(0);
+        let clock = ManualClock::new(test_now());
+        let events = EventBus::new();
+        let mut rx = events.subscribe();
+        let action = new_action(&mut world, to_schedule(clock.now() + Duration::hours(1)));
 
-        process_internal(&mut world, &now);
+        process(&mut world, &clock, &mut rng, &events);
         let action_state = world.get_component::<ActivationState>(action).unwrap();
-        now = now + Duration::hours(1);
-        assert_eq!(*action_state, ActivationState::Scheduled(now.timestamp()));
+        clock.advance(Duration::hours(1));
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(clock.now().timestamp())
+        );
 
-        process_internal(&mut world, &now);
+        process(&mut world, &clock, &mut rng, &events);
         let action_state = world.get_component::<ActivationState>(action).unwrap();
         assert_eq!(*action_state, ActivationState::ReadyToRun);
 
         *action_state = ActivationState::ToBeScheduled;
-        process_internal(&mut world, &now);
+        process(&mut world, &clock, &mut rng, &events);
         assert!(!world.is_alive(action));
+        let id = action.id();
+        assert!(matches!(rx.try_recv(), Ok(Event::TaskScheduled { task_id, .. }) if task_id == id));
+        assert!(matches!(rx.try_recv(), Ok(Event::TaskReadyToRun { task_id }) if task_id == id));
+        assert!(matches!(rx.try_recv(), Ok(Event::TaskRemoved { task_id }) if task_id == id));
 
-        let action = new_action(&mut world, to_schedule(now - Duration::hours(1)));
+        let action = new_action(&mut world, to_schedule(clock.now() - Duration::hours(1)));
         assert!(world.is_alive(action));
-        process_internal(&mut world, &now);
+        process(&mut world, &clock, &mut rng, &events);
         assert!(!world.is_alive(action));
     }
 
+    #[test]
+    fn test_paused_action_is_not_scheduled() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let action = new_action(&mut world, to_schedule(now - Duration::hours(1)));
+        *world.get_component::<ActivationState>(action).unwrap() = ActivationState::Paused;
+
+        let events = process_internal(&mut world, &now, &mut rng);
+        assert!(events.is_empty());
+        assert!(world.is_alive(action));
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        assert_eq!(*action_state, ActivationState::Paused);
+    }
+
     #[test]
     fn test_one_time_action_in_the_past() {
         let mut world = create_world!();
-        let mut now = chrono::Local::now();
-        if now.hour() < 1 {
-            now = now + Duration::hours(1);
-        }
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
 
         let action = new_action(&mut world, to_schedule(now - Duration::hours(1)));
         assert!(world.is_alive(action));
-        process_internal(&mut world, &now);
+        process_internal(&mut world, &now, &mut rng);
         assert!(!world.is_alive(action));
     }
 
     #[test]
     fn test_everyday_action() {
         let mut world = create_world!();
-        let mut now = chrono::Local::now();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut now = test_now();
         let mut scheduled_time = now - Duration::hours(1);
 
-        let mut schedule = to_schedule(scheduled_time.clone());
+        let mut schedule = to_schedule(scheduled_time);
         schedule.weekdays = [true; 7];
         let action = new_action(&mut world, schedule);
 
         let mut repeat = 0;
         while repeat < 8 {
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             scheduled_time = scheduled_time + Duration::days(1);
 
@@ -166,7 +569,7 @@ mod tests {
             }
 
             now = now + Duration::days(1);
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             {
                 let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -180,8 +583,9 @@ mod tests {
     #[test]
     fn test_mult_day_action() {
         let mut world = create_world!();
-        let mut now = chrono::Local::now();
-        let mut schedule = to_schedule(now.clone());
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut now = test_now();
+        let mut schedule = to_schedule(now);
 
         let today = now.weekday().num_days_from_monday() as usize;
         schedule.weekdays[(today + 2) % 7] = true;
@@ -190,7 +594,7 @@ mod tests {
         println!("days {:?}", schedule.weekdays);
         let action = new_action(&mut world, schedule);
 
-        process_internal(&mut world, &now);
+        process_internal(&mut world, &now, &mut rng);
 
         {
             let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -202,7 +606,7 @@ mod tests {
         }
 
         now = now + Duration::days(2);
-        process_internal(&mut world, &now);
+        process_internal(&mut world, &now, &mut rng);
 
         {
             let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -212,7 +616,7 @@ mod tests {
 
         let mut repeat = 0;
         while repeat < 2 {
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             {
                 let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -224,7 +628,7 @@ mod tests {
             }
 
             now = now + Duration::days(4);
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             {
                 let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -232,7 +636,7 @@ mod tests {
                 *action_state = ActivationState::ToBeScheduled;
             }
 
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             {
                 let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -244,7 +648,7 @@ mod tests {
             }
 
             now = now + Duration::days(3);
-            process_internal(&mut world, &now);
+            process_internal(&mut world, &now, &mut rng);
 
             {
                 let action_state = world.get_component::<ActivationState>(action).unwrap();
@@ -258,7 +662,8 @@ mod tests {
     #[test]
     fn add_action_for_next_day_after_current_time() {
         let mut world = create_world!();
-        let now = chrono::Local::now();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
 
         let mut schedule = to_schedule(now + Duration::seconds(1));
 
@@ -267,7 +672,7 @@ mod tests {
         schedule.weekdays[tomorrow] = true;
 
         let action = new_action(&mut world, schedule);
-        process_internal(&mut world, &now);
+        process_internal(&mut world, &now, &mut rng);
         let state = world.get_component::<ActivationState>(action).unwrap();
         let expected_sched_time = now + Duration::seconds(1) + Duration::days(1);
         assert_eq!(
@@ -275,4 +680,499 @@ mod tests {
             ActivationState::Scheduled(expected_sched_time.timestamp())
         );
     }
+
+    #[test]
+    fn test_jitter_within_window() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(42);
+        let now = Local.ymd(2021, 6, 16).and_hms(6, 0, 0);
+        let base_time = Local.ymd(2021, 6, 16).and_hms(6, 30, 0);
+        let mut schedule = to_schedule(base_time);
+        schedule.weekdays = [true; 7];
+        schedule.jitter_min = 15;
+        let action = new_action(&mut world, schedule);
+
+        let mut activation_times = Vec::new();
+        for _ in 0..20 {
+            process_internal(&mut world, &now, &mut rng);
+            let action_state = world.get_component::<ActivationState>(action).unwrap();
+            match *action_state {
+                ActivationState::Scheduled(t) => activation_times.push(t),
+                _ => panic!("action not scheduled"),
+            }
+            *action_state = ActivationState::ToBeScheduled;
+        }
+        let jitter = Duration::minutes(15);
+        for t in &activation_times {
+            assert!(*t >= (base_time - jitter).timestamp());
+            assert!(*t <= (base_time + jitter).timestamp());
+        }
+        assert!(activation_times.iter().any(|t| *t != activation_times[0]));
+    }
+
+    #[test]
+    fn test_early_jittered_run_is_not_repeated() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(42);
+        let base_time = Local.ymd(2021, 6, 16).and_hms(6, 30, 0);
+        let mut schedule = to_schedule(base_time);
+        schedule.weekdays = [true; 7];
+        schedule.jitter_min = 15;
+        let daily = new_action(&mut world, schedule.clone());
+        schedule.weekdays = [false; 7];
+        schedule.date = Some(NaiveDate::from_ymd(2021, 6, 16));
+        let once = new_action(&mut world, schedule);
+
+        process_internal(
+            &mut world,
+            &Local.ymd(2021, 6, 16).and_hms(5, 0, 0),
+            &mut rng,
+        );
+        // both run ten minutes early
+        let early = base_time - Duration::minutes(10);
+        for action in [daily, once] {
+            let state = world.get_component::<ActivationState>(action).unwrap();
+            *state = ActivationState::Scheduled(early.timestamp());
+        }
+        process_internal(&mut world, &early, &mut rng);
+        for action in [daily, once] {
+            let state = world.get_component::<ActivationState>(action).unwrap();
+            assert_eq!(*state, ActivationState::ReadyToRun);
+            // as left by the executor
+            *state = ActivationState::ToBeScheduled;
+        }
+
+        process_internal(&mut world, &(early + Duration::minutes(1)), &mut rng);
+        let state = world.get_component::<ActivationState>(daily).unwrap();
+        let tomorrow = base_time + Duration::days(1) - Duration::minutes(15);
+        assert!(matches!(*state, ActivationState::Scheduled(t) if t >= tomorrow.timestamp()));
+        assert!(!world.is_alive(once));
+    }
+
+    #[test]
+    fn test_jitter_never_in_the_past() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(1);
+        let now = Local.ymd(2021, 6, 16).and_hms(6, 29, 0);
+        let mut schedule = to_schedule(Local.ymd(2021, 6, 16).and_hms(6, 30, 0));
+        schedule.jitter_min = 30;
+        let action = new_action(&mut world, schedule);
+
+        for _ in 0..20 {
+            process_internal(&mut world, &now, &mut rng);
+            let action_state = world.get_component::<ActivationState>(action).unwrap();
+            match *action_state {
+                ActivationState::Scheduled(t) => assert!(t >= now.timestamp()),
+                _ => panic!("action not scheduled"),
+            }
+            *action_state = ActivationState::ToBeScheduled;
+        }
+    }
+
+    #[test]
+    fn test_single_weekday_action_repeats_next_week() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let mut schedule = to_schedule(now - Duration::hours(1));
+        schedule.weekdays[now.weekday().num_days_from_monday() as usize] = true;
+        let action = new_action(&mut world, schedule);
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let scheduled_time = now - Duration::hours(1) + Duration::days(7);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(scheduled_time.timestamp())
+        );
+    }
+
+    #[test]
+    fn test_spring_forward_nonexistent_time_is_shifted() {
+        let now = Berlin.ymd(2021, 3, 27).and_hms(12, 0, 0);
+        let mut schedule = to_schedule(now);
+        schedule.hour = 2;
+        schedule.min = 30;
+        schedule.weekdays = [true; 7];
+
+        let activation = next_activation(&schedule, &now, None).unwrap();
+        assert_eq!(activation, Berlin.ymd(2021, 3, 28).and_hms(3, 0, 0));
+
+        let after = activation + Duration::seconds(1);
+        let activation = next_activation(&schedule, &after, None).unwrap();
+        assert_eq!(activation, Berlin.ymd(2021, 3, 29).and_hms(2, 30, 0));
+    }
+
+    #[test]
+    fn test_spring_forward_keeps_wall_clock_time() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut now = Berlin.ymd(2021, 3, 27).and_hms(6, 30, 0);
+        let mut schedule = to_schedule(now);
+        schedule.weekdays = [true; 7];
+        let action = new_action(&mut world, schedule);
+
+        for day in 28..31 {
+            now = now + Duration::seconds(1);
+            process_internal(&mut world, &now, &mut rng);
+            let action_state = world.get_component::<ActivationState>(action).unwrap();
+            let expected = Berlin.ymd(2021, 3, day).and_hms(6, 30, 0);
+            assert_eq!(
+                *action_state,
+                ActivationState::Scheduled(expected.timestamp())
+            );
+            *action_state = ActivationState::ToBeScheduled;
+            now = expected;
+        }
+        let spring_forward_day = Berlin.ymd(2021, 3, 28).and_hms(6, 30, 0);
+        let day_before = Berlin.ymd(2021, 3, 27).and_hms(6, 30, 0);
+        assert_eq!(spring_forward_day - day_before, Duration::hours(23));
+    }
+
+    #[test]
+    fn test_fall_back_ambiguous_time_uses_first_occurrence() {
+        let now = Berlin.ymd(2021, 10, 30).and_hms(12, 0, 0);
+        let mut schedule = to_schedule(now);
+        schedule.hour = 2;
+        schedule.min = 30;
+        schedule.weekdays = [true; 7];
+
+        let activation = next_activation(&schedule, &now, None).unwrap();
+        assert_eq!(
+            activation.naive_utc(),
+            Utc.ymd(2021, 10, 31).and_hms(0, 30, 0).naive_utc()
+        );
+
+        let after = activation + Duration::seconds(1);
+        let activation = next_activation(&schedule, &after, None).unwrap();
+        assert_eq!(activation, Berlin.ymd(2021, 11, 1).and_hms(2, 30, 0));
+    }
+
+    #[test]
+    fn test_fall_back_keeps_wall_clock_time() {
+        let now = Berlin.ymd(2021, 10, 30).and_hms(7, 0, 0);
+        let mut schedule = to_schedule(Berlin.ymd(2021, 10, 30).and_hms(6, 30, 0));
+        schedule.weekdays = [true; 7];
+
+        let activation = next_activation(&schedule, &now, None).unwrap();
+        assert_eq!(activation, Berlin.ymd(2021, 10, 31).and_hms(6, 30, 0));
+        assert_eq!(
+            activation - Berlin.ymd(2021, 10, 30).and_hms(6, 30, 0),
+            Duration::hours(25)
+        );
+    }
+
+    #[test]
+    fn test_task_time_zone() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = Berlin.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let mut schedule = to_schedule(now);
+        schedule.hour = 7;
+        schedule.min = 0;
+        schedule.weekdays = [true; 7];
+        schedule.timezone = Some("America/New_York".to_owned());
+        let action = new_action(&mut world, schedule);
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = New_York.ymd(2021, 6, 16).and_hms(7, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+    }
+
+    fn alarm_schedule(fallback: AlarmFallback) -> Schedule {
+        Schedule {
+            hour: 7,
+            weekdays: [true; 7],
+            alarm: Some(AlarmOffset {
+                offset_min: -5,
+                fallback,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn push_alarm(world: &mut World, time: DateTime<Local>) -> Entity {
+        let entity = world.new_entity();
+        world.add_component(
+            entity,
+            Alarm {
+                time: time.timestamp(),
+            },
+        );
+        entity
+    }
+
+    #[test]
+    fn test_alarm_relative_action() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let action = new_action(&mut world, alarm_schedule(AlarmFallback::Default));
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 17).and_hms(7, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+
+        *action_state = ActivationState::ToBeScheduled;
+        push_alarm(&mut world, Local.ymd(2021, 6, 17).and_hms(6, 30, 0));
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 17).and_hms(6, 25, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+    }
+
+    #[test]
+    fn test_alarm_pushed_after_run_is_not_repeated() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let action = new_action(&mut world, alarm_schedule(AlarmFallback::Default));
+        let alarm = push_alarm(&mut world, Local.ymd(2021, 6, 17).and_hms(6, 30, 0));
+        process_internal(&mut world, &test_now(), &mut rng);
+
+        let now = Local.ymd(2021, 6, 17).and_hms(6, 25, 0);
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        assert_eq!(*action_state, ActivationState::ReadyToRun);
+        *action_state = ActivationState::ToBeScheduled;
+
+        // snoozed after the task ran
+        world.remove_entity(alarm);
+        push_alarm(&mut world, Local.ymd(2021, 6, 17).and_hms(6, 40, 0));
+        process_internal(&mut world, &(now + chrono::Duration::minutes(1)), &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 18).and_hms(7, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+    }
+
+    #[test]
+    fn test_alarm_relative_action_skips_days_without_alarm() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let action = new_action(&mut world, alarm_schedule(AlarmFallback::Skip));
+
+        process_internal(&mut world, &now, &mut rng);
+        assert!(world.is_alive(action));
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        assert_eq!(*action_state, ActivationState::ToBeScheduled);
+
+        push_alarm(&mut world, Local.ymd(2021, 6, 18).and_hms(8, 0, 0));
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 18).and_hms(7, 55, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+    }
+
+    #[test]
+    fn test_dated_action() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let mut schedule = to_schedule(now - Duration::hours(1));
+        schedule.date = Some(NaiveDate::from_ymd(2021, 6, 20));
+        let action = new_action(&mut world, schedule.clone());
+        schedule.date = Some(NaiveDate::from_ymd(2021, 6, 15));
+        let past = new_action(&mut world, schedule);
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 20).and_hms(11, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+        assert!(!world.is_alive(past));
+    }
+
+    fn holidays(dates: &[NaiveDate]) -> Calendar {
+        Calendar {
+            name: "holidays".to_owned(),
+            dates: dates.iter().cloned().collect(),
+        }
+    }
+
+    fn calendar_schedule(mode: CalendarMode, weekdays: [bool; 7]) -> Schedule {
+        Schedule {
+            hour: 7,
+            weekdays,
+            calendar: Some(CalendarRef {
+                name: "holidays".to_owned(),
+                mode,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_calendar_skips_dates() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let calendar = world.new_entity();
+        let dates = [
+            NaiveDate::from_ymd(2021, 6, 17),
+            NaiveDate::from_ymd(2021, 6, 18),
+        ];
+        world.add_component(calendar, holidays(&dates));
+        let weekdays = [true, true, true, true, true, false, false];
+        let action = new_action(&mut world, calendar_schedule(CalendarMode::Skip, weekdays));
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 6, 21).and_hms(7, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+    }
+
+    #[test]
+    fn test_calendar_only_dates() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let calendar = world.new_entity();
+        let christmas = NaiveDate::from_ymd(2021, 12, 24);
+        world.add_component(calendar, holidays(&[christmas]));
+        let action = new_action(
+            &mut world,
+            calendar_schedule(CalendarMode::Only, [false; 7]),
+        );
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 12, 24).and_hms(7, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+
+        let now = Local.ymd(2021, 12, 24).and_hms(7, 0, 1);
+        *action_state = ActivationState::ToBeScheduled;
+        process_internal(&mut world, &now, &mut rng);
+        assert!(!world.is_alive(action));
+    }
+
+    #[test]
+    fn test_missing_calendar_keeps_tasks_unscheduled() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let only = new_action(
+            &mut world,
+            calendar_schedule(CalendarMode::Only, [false; 7]),
+        );
+        let skip = new_action(&mut world, calendar_schedule(CalendarMode::Skip, [true; 7]));
+
+        process_internal(&mut world, &test_now(), &mut rng);
+        for action in [only, skip] {
+            assert!(world.is_alive(action));
+            let action_state = world.get_component::<ActivationState>(action).unwrap();
+            assert_eq!(*action_state, ActivationState::ToBeScheduled);
+        }
+    }
+
+    #[test]
+    fn test_validity_range() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = test_now();
+        let schedule = Schedule {
+            hour: 14,
+            weekdays: [true, true, true, true, true, false, false],
+            active_from: Some(NaiveDate::from_ymd(2021, 7, 1)),
+            active_until: Some(NaiveDate::from_ymd(2021, 8, 31)),
+            ..Default::default()
+        };
+        let action = new_action(&mut world, schedule);
+
+        process_internal(&mut world, &now, &mut rng);
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        let expected = Local.ymd(2021, 7, 1).and_hms(14, 0, 0);
+        assert_eq!(
+            *action_state,
+            ActivationState::Scheduled(expected.timestamp())
+        );
+
+        let now = Local.ymd(2021, 8, 31).and_hms(14, 0, 1);
+        *action_state = ActivationState::ToBeScheduled;
+        let events = process_internal(&mut world, &now, &mut rng);
+        assert!(world.is_alive(action));
+        let action_state = world.get_component::<ActivationState>(action).unwrap();
+        assert_eq!(*action_state, ActivationState::Expired);
+        assert!(matches!(events[0], Event::TaskExpired { .. }));
+    }
+
+    #[test]
+    fn test_misfire_policies() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = Local.ymd(2021, 6, 18).and_hms(6, 40, 0);
+        let missed = |day| Local.ymd(2021, 6, day).and_hms(6, 30, 0).timestamp();
+        let mut new_missed_task = |misfire, time| {
+            let schedule = Schedule {
+                hour: 6,
+                min: 30,
+                weekdays: [true; 7],
+                misfire,
+                ..Default::default()
+            };
+            let entity = new_action(&mut world, schedule);
+            world.add_component(entity, ActivationState::Scheduled(time));
+            world.add_component(entity, LcnCommand::new(1632));
+            entity
+        };
+        let late = new_missed_task(Misfire::default(), missed(18));
+        let skipped = new_missed_task(Misfire::Skip, missed(18));
+        let all = new_missed_task(Misfire::RunAll, missed(16));
+        let too_late = new_missed_task(Misfire::default(), missed(16));
+
+        let events = process_internal(&mut world, &now, &mut rng);
+        let state = |entity| world.get_component::<ActivationState>(entity).unwrap();
+        assert_eq!(*state(late), ActivationState::ReadyToRun);
+        assert_eq!(*state(skipped), ActivationState::Scheduled(missed(19)));
+        assert_eq!(*state(all), ActivationState::ReadyToRun);
+        assert_eq!(*state(too_late), ActivationState::Scheduled(missed(19)));
+        let catch_up = component_iter!(&world, ActivationState, LcnCommand)
+            .filter(|(_, _, entity)| world.get_component::<Schedule>(**entity).is_none())
+            .count();
+        assert_eq!(catch_up, 2);
+        let misfires: Vec<(i64, u32, u32)> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::TaskMisfired {
+                    task_id,
+                    missed,
+                    runs,
+                    ..
+                } => Some((*task_id, *missed, *runs)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            misfires,
+            vec![
+                (late.id(), 1, 1),
+                (skipped.id(), 1, 0),
+                (all.id(), 3, 3),
+                (too_late.id(), 3, 0)
+            ]
+        );
+    }
 }
@@ -0,0 +1,182 @@
+use super::super::clock::{Clock, ManualClock};
+use super::super::components::*;
+use super::super::events::EventBus;
+use chrono::{Local, TimeZone};
+use lame_ecs::{component_iter, component_iter_mut, Entity, World};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Longest period the API simulates ahead.
+pub const MAX_SIMULATION_DAYS: u32 = 366;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SimulatedRun {
+    pub time: String,
+    pub timestamp: i64,
+    pub task_id: i64,
+    pub cmd: LcnCommand,
+}
+
+pub fn simulate(
+    world: &World,
+    from: chrono::DateTime<Local>,
+    days: u32,
+    rng: &mut impl Rng,
+) -> Vec<SimulatedRun> {
+    simulate_tasks(world, from, days, rng, |_| true)
+}
+
+/// Simulates only the tasks `keep` picks, the others are left out as if
+/// they did not exist.
+pub fn simulate_tasks(
+    world: &World,
+    from: chrono::DateTime<Local>,
+    days: u32,
+    rng: &mut impl Rng,
+    keep: impl Fn(&Entity) -> bool,
+) -> Vec<SimulatedRun> {
+    let end = match from.checked_add_signed(chrono::Duration::days(days as i64)) {
+        Some(end) => end.timestamp(),
+        None => return Vec::new(),
+    };
+    let mut sim = lame_ecs::create_world!();
+    let task_ids = copy_tasks(world, &mut sim, keep);
+    let calendars = super::scheduler::calendars(world);
+    let clock = ManualClock::new(from);
+    // simulated activations must not leak to the subscribers of the real system
+    let events = EventBus::new();
+    let mut runs = Vec::new();
+    loop {
+        super::scheduler::process_with_calendars(&mut sim, &clock, rng, &events, &calendars);
+        let fired = fire_ready_tasks(&mut sim, &clock, &task_ids);
+        if !fired.is_empty() {
+            runs.extend(fired);
+            clock.advance(chrono::Duration::seconds(1));
+            continue;
+        }
+        match next_activation(&sim) {
+            Some(t) if t <= end => clock.set(Local.timestamp(t, 0)),
+            _ => break,
+        }
+    }
+    runs
+}
+
+fn copy_tasks(world: &World, sim: &mut World, keep: impl Fn(&Entity) -> bool) -> HashMap<i64, i64> {
+    let mut task_ids = HashMap::new();
+    for (state, schedule, cmd, entity) in
+        component_iter!(world, ActivationState, Schedule, LcnCommand)
+    {
+        if !keep(entity) {
+            continue;
+        }
+        let copy = sim.new_entity();
+        sim.add_component(copy, state.clone());
+        sim.add_component(copy, schedule.clone());
+        sim.add_component(copy, cmd.clone());
+        if let Some(occurrence) = world.get_component::<Occurrence>(*entity) {
+            sim.add_component(copy, *occurrence);
+        }
+        task_ids.insert(copy.id(), entity.id());
+    }
+    for (alarm, _) in component_iter!(world, Alarm) {
+        let copy = sim.new_entity();
+        sim.add_component(copy, *alarm);
+    }
+    task_ids
+}
+
+fn fire_ready_tasks(
+    sim: &mut World,
+    clock: &dyn Clock,
+    task_ids: &HashMap<i64, i64>,
+) -> Vec<SimulatedRun> {
+    let now = clock.now();
+    let mut fired = Vec::new();
+    for (state, cmd, entity) in component_iter_mut!(sim, ActivationState, LcnCommand) {
+        if *state != ActivationState::ReadyToRun {
+            continue;
+        }
+        *state = ActivationState::ToBeScheduled;
+        // catch up runs for misfires are created by the simulation itself
+        let task_id = match task_ids.get(&entity.id()) {
+            Some(task_id) => *task_id,
+            None => continue,
+        };
+        fired.push(SimulatedRun {
+            time: now.to_rfc3339(),
+            timestamp: now.timestamp(),
+            task_id,
+            cmd: cmd.clone(),
+        });
+    }
+    fired
+}
+
+fn next_activation(sim: &World) -> Option<i64> {
+    component_iter!(sim, ActivationState)
+        .filter_map(|(state, _)| match state {
+            ActivationState::Scheduled(t) => Some(*t),
+            _ => None,
+        })
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lame_ecs::create_world;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn new_task(world: &mut World, hour: i8, min: i8, weekdays: [bool; 7], cmd_id: i32) -> Entity {
+        let entity = world.new_entity();
+        let schedule = Schedule {
+            hour,
+            min,
+            weekdays,
+            ..Default::default()
+        };
+        world.add_component(entity, schedule);
+        world.add_component(entity, ActivationState::ToBeScheduled);
+        world.add_component(entity, LcnCommand::new(cmd_id));
+        entity
+    }
+
+    #[test]
+    fn test_simulate_week() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        let weekdays = [true, true, true, true, true, false, false];
+        let everyday = new_task(&mut world, 6, 30, [true; 7], 1632);
+        let workdays = new_task(&mut world, 7, 0, weekdays, 1623);
+        let once = new_task(&mut world, 18, 0, [false; 7], 1633);
+
+        let from = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let runs = simulate(&world, from, 7, &mut rng);
+
+        let count = |id: Entity| runs.iter().filter(|r| r.task_id == id.id()).count();
+        assert_eq!(count(everyday), 7);
+        assert_eq!(count(workdays), 5);
+        assert_eq!(count(once), 1);
+        assert_eq!(
+            runs[0].timestamp,
+            Local.ymd(2021, 6, 16).and_hms(18, 0, 0).timestamp()
+        );
+        assert_eq!(runs[0].cmd.id, 1633);
+        assert!(runs.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        let state = world.get_component::<ActivationState>(everyday).unwrap();
+        assert_eq!(*state, ActivationState::ToBeScheduled);
+        assert!(world.is_alive(once));
+    }
+
+    #[test]
+    fn test_simulate_out_of_range() {
+        let mut world = create_world!();
+        let mut rng = StdRng::seed_from_u64(0);
+        new_task(&mut world, 6, 30, [true; 7], 1632);
+        let from = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        assert!(simulate(&world, from, u32::MAX, &mut rng).is_empty());
+    }
+}
@@ -1,4 +1,7 @@
 use super::super::components::*;
+use super::super::config::{self, CommandConfig};
+use super::super::requests::StatusOrder;
+use chrono::{DateTime, Local, SecondsFormat, TimeZone};
 use lame_ecs::{component_iter, World};
 use serde::{Deserialize, Serialize};
 
@@ -9,29 +12,126 @@ pub struct TaskStatus {
     pub repeat_days: String,
     pub state: String,
     pub cmd_id: i32,
+    pub installation: String,
+    pub timezone: String,
+    pub validity: String,
+    pub next_run: Option<String>,
+    pub remaining_sec: Option<i64>,
+    pub last_run: Option<String>,
+    pub last_success: Option<bool>,
+    pub failures: u32,
+    pub cmd_name: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub read_only: bool,
 }
 
-pub fn get_status(world: &World) -> Vec<TaskStatus> {
+/// Tasks that will not run again sort last by next run.
+pub fn get_status(
+    world: &World,
+    catalog: &[CommandConfig],
+    now: &DateTime<Local>,
+    order: StatusOrder,
+) -> Vec<TaskStatus> {
+    let calendars: Vec<String> = component_iter!(world, Calendar)
+        .map(|(calendar, _)| calendar.name.clone())
+        .collect();
     let range = component_iter!(world, ActivationState, Schedule, LcnCommand);
 
     let mut report = Vec::<TaskStatus>::new();
     for (state, schedule, cmd, entity) in range {
         let id = entity.id();
-        let activation_time = format!("{:02}:{:02}", schedule.hour, schedule.min);
+        let (activation_time, next_run, remaining_sec) = match state {
+            ActivationState::Scheduled(t) => (
+                format_time(schedule, *t, "%H:%M:%S"),
+                Some(format_rfc3339(schedule, *t)),
+                Some(std::cmp::max(*t - now.timestamp(), 0)),
+            ),
+            _ => (
+                format!(
+                    "{:02}:{:02}:{:02}",
+                    schedule.hour, schedule.min, schedule.sec
+                ),
+                None,
+                None,
+            ),
+        };
+        let last = world.get_component::<LastExecution>(*entity).copied();
+        let metadata = world
+            .get_component::<Metadata>(*entity)
+            .cloned()
+            .unwrap_or_default();
+        let timezone = schedule
+            .timezone
+            .clone()
+            .unwrap_or_else(|| String::from("local"));
         let cmd_id = cmd.id;
+        let installation = cmd.installation.clone();
         let repeat_days = weekdays_to_string(&schedule.weekdays);
-        let state = state_to_string(state);
+        let validity = validity_to_string(schedule);
+        let state = match &schedule.calendar {
+            Some(r) if !calendars.contains(&r.name) => format!("Calendar {} missing", r.name),
+            _ => state_to_string(state),
+        };
         report.push(TaskStatus {
             id,
             activation_time,
             repeat_days,
             state,
             cmd_id,
+            installation,
+            timezone,
+            validity,
+            next_run,
+            remaining_sec,
+            last_run: last.map(|last| format_rfc3339(schedule, last.time)),
+            last_success: last.map(|last| last.success),
+            failures: last.map_or(0, |last| last.failures),
+            cmd_name: config::command_name(catalog, cmd),
+            name: metadata.name,
+            description: metadata.description,
+            tags: metadata.tags.into_iter().collect(),
+            read_only: world.get_component::<FileManaged>(*entity).is_some(),
         });
     }
+    match order {
+        StatusOrder::Id => report.sort_by_key(|status| status.id),
+        StatusOrder::NextRun => {
+            report.sort_by_key(|status| (status.remaining_sec.is_none(), status.remaining_sec))
+        }
+    }
     report
 }
 
+/// Times are shown in the time zone of the task.
+fn format_time(schedule: &Schedule, timestamp: i64, format: &str) -> String {
+    match schedule.timezone() {
+        Ok(Some(tz)) => tz.timestamp(timestamp, 0).format(format).to_string(),
+        _ => Local.timestamp(timestamp, 0).format(format).to_string(),
+    }
+}
+
+fn format_rfc3339(schedule: &Schedule, timestamp: i64) -> String {
+    match schedule.timezone() {
+        Ok(Some(tz)) => tz
+            .timestamp(timestamp, 0)
+            .to_rfc3339_opts(SecondsFormat::Secs, false),
+        _ => Local
+            .timestamp(timestamp, 0)
+            .to_rfc3339_opts(SecondsFormat::Secs, false),
+    }
+}
+
+fn validity_to_string(schedule: &Schedule) -> String {
+    match (schedule.active_from, schedule.active_until) {
+        (Some(from), Some(until)) => format!("{} to {}", from, until),
+        (Some(from), None) => format!("from {}", from),
+        (None, Some(until)) => format!("until {}", until),
+        (None, None) => String::from("always"),
+    }
+}
+
 fn weekdays_to_string(weekdays: &[bool; 7]) -> String {
     let mut result = String::new();
     if weekdays[0] {
@@ -67,5 +167,88 @@ fn state_to_string(state: &ActivationState) -> String {
         ActivationState::ToBeScheduled => String::from("To be scheduled"),
         ActivationState::Scheduled(_) => String::from("Scheduled"),
         ActivationState::ReadyToRun => String::from("Ready to run"),
+        ActivationState::Paused => String::from("Paused"),
+        ActivationState::Expired => String::from("Expired"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::components::schedule::CalendarRef;
+    use super::*;
+    use lame_ecs::create_world;
+
+    #[test]
+    fn test_status_details() {
+        let mut world = create_world!();
+        let now = Local.ymd(2021, 6, 16).and_hms(12, 0, 0);
+        let later = world.new_entity();
+        world.add_component(later, Schedule::default());
+        world.add_component(later, ActivationState::Scheduled(now.timestamp() + 600));
+        world.add_component(later, LcnCommand::new(1681));
+        let sooner = world.new_entity();
+        world.add_component(sooner, Schedule::default());
+        world.add_component(sooner, ActivationState::Scheduled(now.timestamp() + 60));
+        world.add_component(sooner, LcnCommand::new(1623));
+        world.add_component(
+            sooner,
+            Metadata {
+                name: Some("bedroom light".to_owned()),
+                ..Default::default()
+            },
+        );
+        world.add_component(
+            sooner,
+            LastExecution {
+                time: now.timestamp() - 3600,
+                success: false,
+                failures: 2,
+                retries: 0,
+            },
+        );
+        let paused = world.new_entity();
+        world.add_component(paused, Schedule::default());
+        world.add_component(paused, ActivationState::Paused);
+        world.add_component(paused, LcnCommand::new(1623));
+
+        let catalog = config::Config::default().commands();
+        let report = get_status(&world, &catalog, &now, StatusOrder::NextRun);
+        let ids: Vec<i64> = report.iter().map(|status| status.id).collect();
+        assert_eq!(ids, vec![sooner.id(), later.id(), paused.id()]);
+
+        let status = &report[0];
+        assert_eq!(status.remaining_sec, Some(60));
+        let next_run =
+            (now + chrono::Duration::seconds(60)).to_rfc3339_opts(SecondsFormat::Secs, false);
+        assert_eq!(status.next_run, Some(next_run));
+        assert_eq!(status.last_success, Some(false));
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.cmd_name, "SZ Lampe");
+        assert_eq!(status.name.as_deref(), Some("bedroom light"));
+        assert_eq!(report[2].next_run, None);
+        assert_eq!(report[2].activation_time, "00:00:00");
+
+        let report = get_status(&world, &catalog, &now, StatusOrder::Id);
+        let ids: Vec<i64> = report.iter().map(|status| status.id).collect();
+        assert_eq!(ids, vec![later.id(), sooner.id(), paused.id()]);
+    }
+
+    #[test]
+    fn test_missing_calendar_is_reported() {
+        let mut world = create_world!();
+        let task = world.new_entity();
+        let schedule = Schedule {
+            calendar: Some(CalendarRef {
+                name: "holidays".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        world.add_component(task, schedule);
+        world.add_component(task, ActivationState::ToBeScheduled);
+        world.add_component(task, LcnCommand::new(1623));
+
+        let report = get_status(&world, &[], &Local::now(), StatusOrder::Id);
+        assert_eq!(report[0].state, "Calendar holidays missing");
     }
 }
@@ -0,0 +1,110 @@
+use super::backup::{self, ExportedTask};
+use super::requests::*;
+use rocket::tokio::sync::{mpsc::UnboundedSender, oneshot};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The household schedule kept in a file, every task has a unique key that
+/// identifies it across changes of the file.
+#[derive(Debug, Deserialize, Serialize)]
+struct TasksFile {
+    version: u32,
+    tasks: Vec<FileTask>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileTask {
+    pub key: String,
+    #[serde(flatten)]
+    pub task: ExportedTask,
+}
+
+/// Loads the file now and again whenever its modification time changes.
+/// An invalid file is reported and leaves the tasks as they are.
+pub fn start(path: String, requests: UnboundedSender<Request>) {
+    std::thread::spawn(move || {
+        let mut loaded: Option<SystemTime> = None;
+        loop {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            if modified.is_some() && modified != loaded {
+                loaded = modified;
+                match load(&path) {
+                    Ok(tasks) => sync(tasks, &requests),
+                    Err(e) => println!("tasks_file: ignoring {}: {}", path, e),
+                }
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+fn sync(tasks: Vec<FileTask>, requests: &UnboundedSender<Request>) {
+    let (tx, rx) = oneshot::channel();
+    match make_request(requests, rx, Request::SyncFileTasks((tx, tasks))) {
+        Ok(Response::SyncFileTasks(Ok(report))) => println!(
+            "tasks_file: {} tasks added, {} updated, {} removed",
+            report.added.len(),
+            report.updated.len(),
+            report.removed.len()
+        ),
+        Ok(Response::SyncFileTasks(Err(e))) => println!("tasks_file: not applied: {}", e),
+        Ok(_) => println!("tasks_file: unexpected response"),
+        Err(e) => println!("tasks_file: {}", e),
+    }
+}
+
+fn load(path: &str) -> Result<Vec<FileTask>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse(&text)
+}
+
+/// Like task documents, JSON files are told apart by their opening brace.
+fn parse(text: &str) -> Result<Vec<FileTask>, String> {
+    let file: TasksFile = match text.trim_start().starts_with('{') {
+        true => serde_json::from_str(text).map_err(|e| e.to_string())?,
+        false => serde_yaml::from_str(text).map_err(|e| e.to_string())?,
+    };
+    if file.version > backup::VERSION {
+        return Err(format!("unsupported version {}", file.version));
+    }
+    let mut keys = HashSet::new();
+    for task in &file.tasks {
+        if !keys.insert(task.key.as_str()) {
+            return Err(format!("duplicate key {}", task.key));
+        }
+    }
+    Ok(file.tasks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let yaml = "
+version: 1
+tasks:
+  - key: shutters_up
+    schedule: { hour: 7, min: 0, sec: 0, weekdays: [true, true, true, true, true, false, false] }
+    cmd: { id: 1632 }
+    metadata: { name: Shutters up, tags: [bedroom] }
+  - key: light_off
+    schedule: { hour: 23, min: 0, sec: 0, weekdays: [true, true, true, true, true, true, true] }
+    cmd: { id: 1623 }
+    enabled: false
+";
+        let tasks = parse(yaml).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].key, "shutters_up");
+        assert_eq!(tasks[0].task.cmd.id, 1632);
+        assert!(tasks[0].task.metadata.tags.contains("bedroom"));
+        assert!(!tasks[1].task.enabled);
+
+        let duplicate = yaml.replace("light_off", "shutters_up");
+        assert!(parse(&duplicate).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use super::config::{WebhookConfig, WebhookEvent};
+use super::events::{Event, EventBus};
+use chrono::{DateTime, Local};
+use std::collections::HashSet;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Every webhook is delivered by its own thread, a slow or unreachable
+/// endpoint only ever delays its own notifications.
+pub fn start(webhooks: Vec<WebhookConfig>, events: &EventBus) {
+    for webhook in webhooks {
+        let mut rx = events.subscribe();
+        std::thread::spawn(move || {
+            let client = reqwest::blocking::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("could not init http client");
+            let mut auth_lost = HashSet::new();
+            let mut retrying = HashSet::new();
+            while let Some(event) = rx.blocking_recv() {
+                let kind = match classify(&event, &mut auth_lost, &mut retrying) {
+                    Some(kind) if webhook.events.is_empty() || webhook.events.contains(&kind) => {
+                        kind
+                    }
+                    _ => continue,
+                };
+                let payload = render(&webhook, kind, &event, Local::now());
+                if !deliver(
+                    &client,
+                    &webhook.url,
+                    payload,
+                    webhook.retries,
+                    FIRST_BACKOFF,
+                ) {
+                    println!("webhooks: giving up on {:?} for {}", kind, webhook.url);
+                }
+            }
+        });
+    }
+}
+
+/// A lost session is only reported once per installation until one of its
+/// commands succeeds again, retries once per task until it succeeds or
+/// finally fails.
+fn classify(
+    event: &Event,
+    auth_lost: &mut HashSet<String>,
+    retrying: &mut HashSet<i64>,
+) -> Option<WebhookEvent> {
+    match event {
+        Event::CommandExecuted {
+            task_id,
+            cmd,
+            success,
+            ..
+        } if *success => {
+            auth_lost.remove(&cmd.installation);
+            retrying.remove(task_id);
+            Some(WebhookEvent::Executed)
+        }
+        Event::CommandExecuted {
+            task_id,
+            retrying: true,
+            ..
+        } => match retrying.insert(*task_id) {
+            true => Some(WebhookEvent::Retried),
+            false => None,
+        },
+        Event::CommandExecuted { task_id, .. } => {
+            retrying.remove(task_id);
+            Some(WebhookEvent::Failed)
+        }
+        Event::TaskCreated { .. } => Some(WebhookEvent::TaskCreated),
+        Event::TaskRemoved { .. } => Some(WebhookEvent::TaskRemoved),
+        Event::AuthLost { installation } if auth_lost.insert(installation.clone()) => {
+            Some(WebhookEvent::AuthLost)
+        }
+        _ => None,
+    }
+}
+
+/// Without a template the event itself is sent. Templates may use
+/// `{{event}}`, `{{task_id}}`, `{{cmd_id}}`, `{{installation}}`, `{{time}}`
+/// and `{{payload}}`, string values are JSON escaped.
+fn render(
+    webhook: &WebhookConfig,
+    kind: WebhookEvent,
+    event: &Event,
+    now: DateTime<Local>,
+) -> String {
+    let payload = serde_json::to_string(event).unwrap();
+    let template = match &webhook.template {
+        Some(template) => template,
+        None => return payload,
+    };
+    let (task_id, cmd) = match event {
+        Event::TaskCreated { task_id, cmd, .. } | Event::CommandExecuted { task_id, cmd, .. } => {
+            (Some(*task_id), Some(cmd))
+        }
+        Event::TaskRemoved { task_id } => (Some(*task_id), None),
+        _ => (None, None),
+    };
+    let installation = match event {
+        Event::AuthLost { installation } => Some(installation.clone()),
+        _ => cmd.map(|cmd| cmd.installation.clone()),
+    };
+    let kind = serde_json::to_string(&kind).unwrap();
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    template
+        .replace("{{event}}", kind.trim_matches('"'))
+        .replace("{{task_id}}", &optional(task_id.map(|id| id.to_string())))
+        .replace("{{cmd_id}}", &optional(cmd.map(|cmd| cmd.id.to_string())))
+        .replace("{{installation}}", &escape(&optional(installation)))
+        .replace("{{time}}", &now.to_rfc3339())
+        .replace("{{payload}}", &payload)
+}
+
+fn escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_owned()
+}
+
+fn deliver(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    payload: String,
+    retries: u32,
+    mut backoff: Duration,
+) -> bool {
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        let response = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.clone())
+            .send();
+        match response {
+            Ok(r) if r.status().is_success() => return true,
+            Ok(r) => println!("webhooks: {} answered {}", url, r.status()),
+            Err(e) => println!("webhooks: could not reach {}: {}", url, e),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::components::LcnCommand;
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn failed(installation: &str, retrying: bool) -> Event {
+        let mut cmd = LcnCommand::new(1632);
+        cmd.installation = installation.to_owned();
+        Event::CommandExecuted {
+            task_id: 2,
+            cmd,
+            success: false,
+            retrying,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let mut auth_lost = HashSet::new();
+        let mut retrying = HashSet::new();
+        let mut classify = |event: &Event| classify(event, &mut auth_lost, &mut retrying);
+        let lost = Event::AuthLost {
+            installation: "default".to_owned(),
+        };
+        assert_eq!(
+            classify(&failed("default", true)),
+            Some(WebhookEvent::Retried)
+        );
+        assert_eq!(classify(&failed("default", true)), None);
+        assert_eq!(
+            classify(&failed("default", false)),
+            Some(WebhookEvent::Failed)
+        );
+        assert_eq!(
+            classify(&failed("default", true)),
+            Some(WebhookEvent::Retried)
+        );
+        assert_eq!(classify(&lost), Some(WebhookEvent::AuthLost));
+        assert_eq!(classify(&lost), None);
+
+        let succeeded = Event::CommandExecuted {
+            task_id: 2,
+            cmd: LcnCommand::new(1632),
+            success: true,
+            retrying: false,
+        };
+        assert_eq!(classify(&succeeded), Some(WebhookEvent::Executed));
+        assert_eq!(classify(&lost), Some(WebhookEvent::AuthLost));
+        assert_eq!(
+            classify(&failed("default", true)),
+            Some(WebhookEvent::Retried)
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        let webhook = WebhookConfig {
+            url: String::new(),
+            events: Vec::new(),
+            template: Some(
+                r#"{"text": "{{event}}: task {{task_id}} command {{cmd_id}} on {{installation}} at {{time}}"}"#
+                    .to_owned(),
+            ),
+            retries: 0,
+        };
+        let now = Local.ymd(2021, 6, 16).and_hms(7, 0, 0);
+        let payload = render(&webhook, WebhookEvent::Failed, &failed("a\"b", false), now);
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        let expected = format!(
+            "failed: task 2 command 1632 on a\"b at {}",
+            now.to_rfc3339()
+        );
+        assert_eq!(payload["text"], expected);
+    }
+
+    #[test]
+    fn test_deliver_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !String::from_utf8_lossy(&request).ends_with("\"ok\"}") {
+                    let n = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+                bodies.push(String::from_utf8_lossy(&request).into_owned());
+            }
+            bodies
+        });
+        let client = reqwest::blocking::Client::new();
+        let payload = r#"{"status":"ok"}"#.to_owned();
+        assert!(deliver(
+            &client,
+            &url,
+            payload,
+            2,
+            Duration::from_millis(10)
+        ));
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert!(bodies[1].starts_with("POST /hook"));
+    }
+}